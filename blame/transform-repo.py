@@ -223,11 +223,11 @@ def transform_revision(commit):
     except:
         reference = 'refs/heads/master'
 
+    # See docs/blame.md for the message format.
+    msg = 'blame-format 1\ngit %s\n' % commit.id
     hg_id = git_to_hg_map.get(commit.id)
     if hg_id:
-        msg = 'git %s\nhg %s\n' % (commit.id, hg_id)
-    else:
-        msg = 'git %s\n' % commit.id
+        msg += 'hg %s\n' % hg_id
 
     with Timer("Commit"):
         oid = new_repo.create_commit(reference,
@@ -251,6 +251,23 @@ def index_mercurial(map_file):
 
     return m
 
+def original_rev(msg):
+    lines = [ line.split() for line in msg.splitlines() if line.strip() ]
+    if lines and lines[0][0] == 'blame-format':
+        lines = lines[1:]
+    else:
+        # Old messages have the pairs all on one line.
+        words = msg.split()
+        lines = [ words[i:i + 2] for i in range(0, len(words), 2) ]
+
+    for words in lines:
+        if len(words) == 2 and words[0] == 'git':
+            try:
+                return pygit2.Oid(hex=words[1])
+            except ValueError:
+                return None
+    return None
+
 def index_existing():
     try:
         new_repo.head.target
@@ -259,7 +276,10 @@ def index_existing():
 
     blame_map = {}
     for commit in new_repo.walk(new_repo.head.target):
-        orig = pygit2.Oid(hex=commit.message.split()[1])
+        orig = original_rev(commit.message)
+        if orig is None:
+            print 'Skipping blame commit', commit.id
+            continue
         blame_map[orig] = commit
 
     return blame_map
@@ -34,6 +34,7 @@ for repo in config['trees']:
   location /%(repo)s/source {
     root %(doc_root)s;
     try_files /file/$uri /dir/$uri/index.html =404;
+    gzip_static on;
     types {
       image/png png;
       image/jpeg jpeg;
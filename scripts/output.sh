@@ -22,6 +22,10 @@ then
     FILTER=".*"
 fi
 
+# Build or refresh the blame map once, so that the output-file
+# processes below only map it instead of each building it.
+$MOZSEARCH_PATH/tools/target/release/check-config --indexes $CONFIG_FILE $TREE_NAME
+
 cat $INDEX_ROOT/repo-files $INDEX_ROOT/objdir-files | grep "$FILTER" | \
     parallel --files --halt 2 -X --eta \
 	     $MOZSEARCH_PATH/tools/target/release/output-file $CONFIG_FILE $TREE_NAME
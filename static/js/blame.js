@@ -18,7 +18,7 @@ function updateBlamePopup() {
   var elt = blameElt;
   var blame = elt.dataset.blame;
 
-  var [rev, filespec, lineno] = blame.split("#");
+  var [rev, filespec, lineno, skipped] = blame.split("#");
   var path = $("#data").data("path");
   var tree = $("#data").data("tree");
   if (filespec != "%") {
@@ -37,11 +37,18 @@ function updateBlamePopup() {
 
     var content = json.header;
 
+    if (skipped) {
+      var skippedLinks = skipped.split(",").map(function(skippedRev) {
+        return `<a href="/${tree}/commit/${skippedRev}">${skippedRev.substr(0, 12)}</a>`;
+      });
+      content += `<br><i>Skipped ignored revisions: ${skippedLinks.join(", ")}</i>`;
+    }
+
     var diffLink = `/${tree}/diff/${rev}/${path}#${lineno}`;
     content += `<br><a href="${diffLink}">Show annotated diff</a>`;
 
     if (json.parent) {
-      var parentLink = `/${tree}/rev/${json.parent}/${path}#${lineno}`;
+      var parentLink = `/${tree}/blame-prev/${rev}/${path}?line=${lineno}`;
       content += `<br><a href="${parentLink}" class="deemphasize">Show latest version without this line</a>`;
     }
 
@@ -118,3 +125,10 @@ function blameHoverHandler(event) {
 $(".blame-strip").on("mouseenter", blameHoverHandler);
 $(".blame-strip").on("mouseleave", blameHoverHandler);
 
+
+/* Blame age heatmap */
+if (/[?&]blame=age\b/.test(window.location.search)) {
+  $("body").addClass("blame-age");
+  $("a[href='?blame=age']").attr("href", window.location.pathname).attr("title", "Color by revision")
+    .text("Color by revision");
+}
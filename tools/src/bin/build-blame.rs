@@ -0,0 +1,542 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::path::Path;
+use std::process;
+
+extern crate getopts;
+extern crate git2;
+extern crate tools;
+extern crate unicode_normalization;
+
+use getopts::Options;
+use git2::{Blob, Commit, DiffFindOptions, ObjectType, Oid, Repository, Tree};
+use tools::blame::{map_lines, read_ignore_revs, BlameLine};
+use tools::blame_map::{BlameMap, BLAME_FORMAT_VERSION};
+use unicode_normalization::UnicodeNormalization;
+
+// Extends a blame repository (see docs/blame.md) with blame commits
+// for every commit of the original repository that doesn't have one
+// yet.
+struct BlameBuilder<'a> {
+    repo: &'a Repository,
+    blame_repo: &'a Repository,
+
+    // Blame commits that were already in the blame repository.
+    existing: BlameMap,
+    // Blame commits created by this run.
+    created: HashMap<Oid, Oid>,
+
+    hg_map: HashMap<Oid, String>,
+    ignore_revs: HashSet<Oid>,
+}
+
+fn read_hg_map(path: &str) -> Result<HashMap<Oid, String>, String> {
+    let file = try!(File::open(path).map_err(|err| format!("Unable to open {}: {}", path, err)));
+    let mut map = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let line = try!(line.map_err(|err| format!("Unable to read {}: {}", path, err)));
+        let pieces = line.split_whitespace().collect::<Vec<_>>();
+        if pieces.is_empty() {
+            continue;
+        }
+        let git_rev = match (pieces.len(), Oid::from_str(pieces[0])) {
+            (2, Ok(oid)) => oid,
+            _ => return Err(format!("Bad line in {}: {}", path, line)),
+        };
+        map.insert(git_rev, pieces[1].to_owned());
+    }
+    Ok(map)
+}
+
+fn split_lines(data: &str) -> Vec<&str> {
+    if data.is_empty() {
+        return vec![];
+    }
+    let data = if data.ends_with('\n') { &data[.. data.len() - 1] } else { data };
+    data.split('\n').collect()
+}
+
+fn blame_line(rev: Oid, path: &str, lineno: usize, author: &str) -> String {
+    format!("{}:{}:{}:{}", rev, path, lineno, author)
+}
+
+// Authors are folded to ASCII the way transform-repo.py does it, so
+// that blame repositories built by either one agree: accents are
+// removed and other non-ASCII characters are dropped.
+fn blame_author(name: &[u8]) -> String {
+    String::from_utf8_lossy(name).nfkd().filter(|&c| c <= '\x7f').collect::<String>().replace('\n', " ")
+}
+
+// Blame lines give the path as `%` when it's the same as the path of
+// the file they belong to. Fills in the path for a line taken from a
+// file that was renamed, and records `skipped` if the line was changed
+// by an ignored revision.
+fn fixup_blame(line: &str, path: &str, parent_path: &str, skipped: Option<Oid>) -> String {
+    let mut blame_line = match BlameLine::parse(line) {
+        Some(blame_line) => blame_line,
+        None => return line.to_owned(),
+    };
+    if path != parent_path && blame_line.path == "%" {
+        blame_line.path = parent_path.to_owned();
+    }
+    if let Some(rev) = skipped {
+        blame_line.skipped.push(rev.to_string());
+    }
+    blame_line.to_string()
+}
+
+fn subtree<'r>(repo: &'r Repository, tree: Option<&Tree>, name: &str) -> Option<Tree<'r>> {
+    let entry = match tree.and_then(|tree| tree.get_name(name)) {
+        Some(entry) => entry,
+        None => return None,
+    };
+    if entry.kind() != Some(ObjectType::Tree) {
+        return None;
+    }
+    repo.find_tree(entry.id()).ok()
+}
+
+fn find_blob<'r>(repo: &'r Repository, commit: &Commit, path: &str) -> Option<Blob<'r>> {
+    let tree = match commit.tree() {
+        Ok(tree) => tree,
+        Err(_) => return None,
+    };
+    match tree.get_path(Path::new(path)) {
+        Ok(ref entry) if entry.kind() == Some(ObjectType::Blob) => repo.find_blob(entry.id()).ok(),
+        _ => None,
+    }
+}
+
+impl<'a> BlameBuilder<'a> {
+    fn blame_commit(&self, oid: Oid) -> Option<Oid> {
+        match self.created.get(&oid) {
+            Some(blame_oid) => Some(*blame_oid),
+            None => self.existing.get(&oid),
+        }
+    }
+
+    // Maps blob ids in `commit` to the path they were renamed or
+    // copied from in its parent.
+    fn find_movement(&self, commit: &Commit) -> HashMap<Oid, String> {
+        let mut movement = HashMap::new();
+        if commit.parent_count() != 1 {
+            return movement;
+        }
+
+        let parent_tree = commit.parent(0).and_then(|parent| parent.tree());
+        let tree = commit.tree();
+        let mut diff = match (parent_tree, tree) {
+            (Ok(parent_tree), Ok(tree)) => {
+                match self.repo.diff_tree_to_tree(Some(&parent_tree), Some(&tree), None) {
+                    Ok(diff) => diff,
+                    Err(_) => return movement,
+                }
+            },
+            _ => return movement,
+        };
+
+        let mut find_opts = DiffFindOptions::new();
+        find_opts.renames(true);
+        find_opts.copies(true);
+        find_opts.rename_limit(1000000);
+        if diff.find_similar(Some(&mut find_opts)).is_err() {
+            return movement;
+        }
+
+        for delta in diff.deltas() {
+            let old_path = delta.old_file().path().and_then(|p| p.to_str());
+            let new_path = delta.new_file().path().and_then(|p| p.to_str());
+            if let (Some(old_path), Some(new_path)) = (old_path, new_path) {
+                if old_path != new_path {
+                    movement.insert(delta.new_file().id(), old_path.to_owned());
+                }
+            }
+        }
+        movement
+    }
+
+    fn blame_for_path(&self,
+                      commit: &Commit,
+                      blame_parents: &[Commit],
+                      movement: &HashMap<Oid, String>,
+                      path: &str,
+                      blob: &Blob) -> String {
+        let author = blame_author(commit.author().name_bytes());
+
+        let mut blame = (1 .. String::from_utf8_lossy(blob.content()).lines().count() + 1).map(|lineno| {
+            blame_line(commit.id(), "%", lineno, &author)
+        }).collect::<Vec<_>>();
+        let mut from_parent = vec![false; blame.len()];
+
+        let parent_path = match movement.get(&blob.id()) {
+            Some(old_path) => old_path.as_str(),
+            None => path,
+        };
+        let parents = commit.parents().zip(blame_parents.iter()).filter_map(|(parent, blame_parent)| {
+            let parent_blob = match find_blob(self.repo, &parent, parent_path) {
+                Some(blob) => blob,
+                None => return None,
+            };
+            let parent_blame_blob = match find_blob(self.blame_repo, blame_parent, parent_path) {
+                Some(blob) => blob,
+                None => return None,
+            };
+            let parent_blame = String::from_utf8_lossy(parent_blame_blob.content()).into_owned();
+            Some((parent.id(), parent_blob, parent_blame))
+        }).collect::<Vec<_>>();
+
+        // Lines that are unchanged from the first parent win, so it
+        // goes last.
+        for &(_, ref parent_blob, ref parent_blame) in parents.iter().rev() {
+            let parent_blame = split_lines(parent_blame);
+            for (lineno, parent_lineno) in map_lines(parent_blob, blob, false) {
+                if lineno < blame.len() && parent_lineno < parent_blame.len() {
+                    blame[lineno] = fixup_blame(parent_blame[parent_lineno], path, parent_path, None);
+                    from_parent[lineno] = true;
+                }
+            }
+        }
+
+        // Lines that an ignored revision changed keep the blame of the
+        // corresponding line in its first parent.
+        if self.ignore_revs.contains(&commit.id()) {
+            if let Some(&(parent_id, ref parent_blob, ref parent_blame)) = parents.first() {
+                if parent_id == commit.parent_id(0).unwrap() {
+                    let parent_blame = split_lines(parent_blame);
+                    for (lineno, parent_lineno) in map_lines(parent_blob, blob, true) {
+                        if lineno < blame.len() && !from_parent[lineno] && parent_lineno < parent_blame.len() {
+                            blame[lineno] = fixup_blame(parent_blame[parent_lineno], path, parent_path,
+                                                        Some(commit.id()));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut result = String::new();
+        for line in blame {
+            result.push_str(&line);
+            result.push('\n');
+        }
+        result
+    }
+
+    // Writes the blame tree for the directory `path` of `commit`. Files
+    // that are the same as in a parent reuse that parent's blame.
+    fn build_tree(&self,
+                  commit: &Commit,
+                  blame_parents: &[Commit],
+                  movement: &HashMap<Oid, String>,
+                  path: &str,
+                  tree: &Tree,
+                  parent_trees: &[Option<Tree>],
+                  blame_parent_trees: &[Option<Tree>]) -> Result<Oid, git2::Error> {
+        let mut builder = try!(self.blame_repo.treebuilder(None));
+
+        for entry in tree.iter() {
+            let name = match entry.name() {
+                Some(name) => name,
+                None => {
+                    println!("Skipping non-UTF-8 file name in {}/{}", commit.id(), path);
+                    continue;
+                },
+            };
+
+            let mut reused = None;
+            for (parent_tree, blame_parent_tree) in parent_trees.iter().zip(blame_parent_trees.iter()) {
+                let parent_entry = parent_tree.as_ref().and_then(|t| t.get_name(name));
+                if parent_entry.map(|e| e.id()) != Some(entry.id()) {
+                    continue;
+                }
+                if let Some(blame_entry) = blame_parent_tree.as_ref().and_then(|t| t.get_name(name)) {
+                    reused = Some(blame_entry.id());
+                    break;
+                }
+            }
+            if let Some(oid) = reused {
+                try!(builder.insert(name, oid, entry.filemode()));
+                continue;
+            }
+
+            let entry_path = if path.is_empty() { name.to_owned() } else { format!("{}/{}", path, name) };
+            match entry.kind() {
+                Some(ObjectType::Blob) => {
+                    let blob = try!(self.repo.find_blob(entry.id()));
+                    let blame = self.blame_for_path(commit, blame_parents, movement, &entry_path, &blob);
+                    let oid = try!(self.blame_repo.blob(blame.as_bytes()));
+                    try!(builder.insert(name, oid, entry.filemode()));
+                },
+                Some(ObjectType::Tree) => {
+                    let subtree_obj = try!(self.repo.find_tree(entry.id()));
+                    let sub_parents = parent_trees.iter().map(|t| {
+                        subtree(self.repo, t.as_ref(), name)
+                    }).collect::<Vec<_>>();
+                    let sub_blame_parents = blame_parent_trees.iter().map(|t| {
+                        subtree(self.blame_repo, t.as_ref(), name)
+                    }).collect::<Vec<_>>();
+                    let oid = try!(self.build_tree(commit, blame_parents, movement, &entry_path,
+                                                   &subtree_obj, &sub_parents, &sub_blame_parents));
+                    try!(builder.insert(name, oid, entry.filemode()));
+                },
+                // Submodules have no blame.
+                _ => {},
+            }
+        }
+
+        builder.write()
+    }
+
+    fn message(&self, commit: &Commit) -> String {
+        let mut msg = format!("blame-format {}\ngit {}\n", BLAME_FORMAT_VERSION, commit.id());
+        if let Some(hg_id) = self.hg_map.get(&commit.id()) {
+            msg.push_str(&format!("hg {}\n", hg_id));
+        }
+        msg
+    }
+
+    // Creates the blame commit for `commit`, whose parents must already
+    // have blame commits.
+    fn transform(&mut self, commit: &Commit) -> Result<Oid, git2::Error> {
+        let mut blame_parents = vec![];
+        for parent_id in commit.parent_ids() {
+            let blame_oid = self.blame_commit(parent_id).expect("parent has no blame commit");
+            blame_parents.push(try!(self.blame_repo.find_commit(blame_oid)));
+        }
+
+        let movement = self.find_movement(commit);
+        let tree = try!(commit.tree());
+        let parent_trees = commit.parents().map(|p| p.tree().ok()).collect::<Vec<_>>();
+        let blame_parent_trees = blame_parents.iter().map(|p| p.tree().ok()).collect::<Vec<_>>();
+        let tree_oid = try!(self.build_tree(commit, &blame_parents, &movement, "", &tree,
+                                            &parent_trees, &blame_parent_trees));
+        let blame_tree = try!(self.blame_repo.find_tree(tree_oid));
+
+        let parent_refs = blame_parents.iter().collect::<Vec<_>>();
+        let blame_oid = try!(self.blame_repo.commit(None, &commit.author(), &commit.committer(),
+                                                    &self.message(commit), &blame_tree, &parent_refs));
+        self.created.insert(commit.id(), blame_oid);
+        Ok(blame_oid)
+    }
+
+    // Points the blame repository's HEAD at `blame_oid`.
+    fn set_head(&self, blame_oid: Oid) -> Result<(), git2::Error> {
+        let head = try!(self.blame_repo.find_reference("HEAD"));
+        let branch = head.symbolic_target().unwrap_or("refs/heads/master").to_owned();
+        try!(self.blame_repo.reference(&branch, blame_oid, true, "build-blame"));
+        Ok(())
+    }
+
+    // Transforms every commit reachable from `head` that doesn't have a
+    // blame commit yet, parents first. Returns the number of commits
+    // transformed.
+    fn run(&mut self, head: Oid) -> Result<usize, git2::Error> {
+        let mut todo = vec![];
+        let mut walk = try!(self.repo.revwalk());
+        try!(walk.push(head));
+        for oid in walk {
+            let oid = try!(oid);
+            if self.blame_commit(oid).is_none() {
+                todo.push(oid);
+            }
+        }
+        println!("{} commits to transform", todo.len());
+
+        let mut count = 0;
+        for oid in todo.into_iter().rev() {
+            let mut stack = vec![oid];
+            loop {
+                let top = match stack.last() {
+                    Some(&top) => top,
+                    None => break,
+                };
+                if self.blame_commit(top).is_some() {
+                    stack.pop();
+                    continue;
+                }
+
+                let commit = try!(self.repo.find_commit(top));
+                let missing = commit.parent_ids().filter(|p| self.blame_commit(*p).is_none()).collect::<Vec<_>>();
+                if !missing.is_empty() {
+                    stack.extend(missing);
+                    continue;
+                }
+
+                count += 1;
+                let blame_oid = try!(self.transform(&commit));
+                try!(self.set_head(blame_oid));
+                println!("Transformed {} -> {} ({})", top, blame_oid, count);
+                stack.pop();
+            }
+        }
+
+        if let Some(blame_oid) = self.blame_commit(head) {
+            try!(self.set_head(blame_oid));
+        }
+        Ok(count)
+    }
+}
+
+fn get_options_parser() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("", "hg-map", "File of `<git-rev> <hg-rev>` lines to record hg revisions from.", "FILE");
+    opts.optopt("", "ignore-revs", "File of revisions, one per line, whose changes blame should skip.", "FILE");
+    opts.optflag("h", "help", "Print this help message.");
+    opts
+}
+
+fn print_usage(opts: &Options) {
+    let brief = "Usage: build-blame [options] git-repo blame-repo";
+    print!("{}", opts.usage(brief));
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+
+    let opts = get_options_parser();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => { m },
+        Err(f) => { panic!(f.to_string()) },
+    };
+    if matches.opt_present("h") || matches.free.len() != 2 {
+        print_usage(&opts);
+        return;
+    }
+
+    let repo_path = &matches.free[0];
+    let blame_path = &matches.free[1];
+
+    let repo = match Repository::open(repo_path) {
+        Ok(repo) => repo,
+        Err(err) => {
+            println!("Unable to open {}: {}", repo_path, err);
+            process::exit(1);
+        },
+    };
+
+    let blame_repo = if Path::new(blame_path).exists() {
+        Repository::open(blame_path)
+    } else {
+        Repository::init_bare(blame_path)
+    };
+    let blame_repo = match blame_repo {
+        Ok(blame_repo) => blame_repo,
+        Err(err) => {
+            println!("Unable to open {}: {}", blame_path, err);
+            process::exit(1);
+        },
+    };
+
+    let hg_map = match matches.opt_str("hg-map") {
+        Some(path) => match read_hg_map(&path) {
+            Ok(map) => map,
+            Err(err) => {
+                println!("{}", err);
+                process::exit(1);
+            },
+        },
+        None => HashMap::new(),
+    };
+
+    let ignore_revs = match matches.opt_str("ignore-revs") {
+        Some(path) => match read_ignore_revs(&path) {
+            Ok(revs) => revs,
+            Err(err) => {
+                println!("{}", err);
+                process::exit(1);
+            },
+        },
+        None => HashSet::new(),
+    };
+
+    let head = match repo.refname_to_id("HEAD") {
+        Ok(head) => head,
+        Err(err) => {
+            println!("Unable to find HEAD of {}: {}", repo_path, err);
+            process::exit(1);
+        },
+    };
+
+    let mut builder = BlameBuilder {
+        repo: &repo,
+        blame_repo: &blame_repo,
+        existing: BlameMap::new(blame_path),
+        created: HashMap::new(),
+        hg_map: hg_map,
+        ignore_revs: ignore_revs,
+    };
+
+    match builder.run(head) {
+        Ok(count) => println!("Transformed {} commits", count),
+        Err(err) => {
+            println!("Error building blame: {}", err);
+            process::exit(1);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tools::testing::{commit, TempDir};
+
+    fn read_blame(blame_repo: &Repository, blame_oid: Oid, path: &str) -> Vec<String> {
+        let blame_commit = blame_repo.find_commit(blame_oid).unwrap();
+        let blob = find_blob(blame_repo, &blame_commit, path).unwrap();
+        String::from_utf8_lossy(blob.content()).lines().map(|l| l.to_owned()).collect()
+    }
+
+    #[test]
+    fn test_blame_author() {
+        assert_eq!(blame_author("Andr\u{e9} Bargull".as_bytes()), "Andre Bargull");
+        assert_eq!(blame_author("\u{fb01}sh \u{65e5}\u{672c}".as_bytes()), "fish ");
+    }
+
+    #[test]
+    fn test_fixup_blame() {
+        let rev = "0123456789abcdef0123456789abcdef01234567";
+        let line = format!("{}:%:3:A", rev);
+        assert_eq!(fixup_blame(&line, "a.cpp", "a.cpp", None), line);
+        assert_eq!(fixup_blame(&line, "b.cpp", "a.cpp", None), format!("{}:a.cpp:3:A", rev));
+
+        // Lines that already name another path keep it.
+        let line = format!("{}:old.cpp:3:A", rev);
+        assert_eq!(fixup_blame(&line, "b.cpp", "a.cpp", None), line);
+    }
+
+    #[test]
+    fn test_blame_across_rename() {
+        let dir = TempDir::new("build-blame");
+        let repo = Repository::init_bare(dir.join("repo")).unwrap();
+        let blame_repo = Repository::init_bare(dir.join("blame")).unwrap();
+        let content = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\n";
+        let first = commit(&repo, "Andr\u{e9}", &[("a.txt", content)], "Add a.txt", &[]);
+        let renamed = "one\ntwo\nthree\nnew\nfour\nfive\nsix\nseven\neight\n";
+        let second = commit(&repo, "Bea", &[("b.txt", renamed)], "Rename to b.txt", &[first]);
+
+        let mut builder = BlameBuilder {
+            repo: &repo,
+            blame_repo: &blame_repo,
+            existing: BlameMap::empty(),
+            created: HashMap::new(),
+            hg_map: HashMap::new(),
+            ignore_revs: HashSet::new(),
+        };
+        assert_eq!(builder.run(second).unwrap(), 2);
+
+        let blame = read_blame(&blame_repo, builder.created[&first], "a.txt");
+        assert_eq!(blame[0], format!("{}:%:1:Andre", first));
+
+        // Unchanged lines keep their line numbers in the first commit,
+        // with the path they had there, and the added line is blamed
+        // on the rename.
+        let blame = read_blame(&blame_repo, builder.created[&second], "b.txt");
+        assert_eq!(blame.len(), 9);
+        assert_eq!(blame[2], format!("{}:a.txt:3:Andre", first));
+        assert_eq!(blame[3], format!("{}:%:4:Bea", second));
+        assert_eq!(blame[4], format!("{}:a.txt:4:Andre", first));
+        assert_eq!(blame[8], format!("{}:a.txt:8:Andre", first));
+    }
+}
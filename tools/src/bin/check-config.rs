@@ -0,0 +1,74 @@
+use std::env;
+use std::process;
+
+extern crate getopts;
+extern crate tools;
+
+use getopts::Options;
+use tools::config;
+
+fn get_options_parser() -> Options {
+    let mut opts = Options::new();
+    opts.optflag("i", "indexes",
+                 "Also read the blame repository of each tree, as web-server does.");
+    opts.optflag("h", "help", "Print this help message.");
+    opts
+}
+
+fn print_usage(opts: &Options) {
+    let brief = "Usage: check-config [options] config.json [tree ...]";
+    print!("{}", opts.usage(brief));
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+
+    let opts = get_options_parser();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => { m },
+        Err(f) => { panic!(f.to_string()) },
+    };
+    if matches.opt_present("h") || matches.free.is_empty() {
+        print_usage(&opts);
+        return;
+    }
+    let need_indexes = matches.opt_present("i");
+
+    let config_path = &matches.free[0];
+    let tree_names = &matches.free[1..];
+    let tree_names = if tree_names.is_empty() { None } else { Some(tree_names) };
+
+    let cfg = match config::load_trees(config_path, need_indexes, tree_names) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        },
+    };
+
+    let unknown = match config::unknown_keys(config_path, tree_names) {
+        Ok(errors) => errors,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        },
+    };
+
+    println!("mozsearch_path: {}", cfg.mozsearch_path);
+    for (tree_name, tree_config) in &cfg.trees {
+        let history = match tree_config.git {
+            Some(ref git) if need_indexes => format!("{} blame revisions", git.blame_map.len()),
+            Some(_) => "yes".to_owned(),
+            None => "no".to_owned(),
+        };
+        println!("{}: ok (history: {}, commit link rules: {})",
+                 tree_name, history, tree_config.commit_links.len());
+    }
+
+    for err in &unknown {
+        println!("{}", err);
+    }
+    if !unknown.is_empty() {
+        process::exit(1);
+    }
+}
@@ -1,5 +1,6 @@
 use std::fs::File;
 use std::env;
+use std::process;
 use std::io::BufReader;
 use std::io::BufRead;
 use std::io::Write;
@@ -64,10 +65,22 @@ fn split_scopes(id: &str) -> Vec<String> {
 fn main() {
     let args: Vec<_> = env::args().collect();
 
-    let cfg = config::load(&args[1], false);
+    let cfg = match config::load(&args[1], false) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        },
+    };
 
     let tree_name = &args[2];
-    let tree_config = cfg.trees.get(tree_name).unwrap();
+    let tree_config = match cfg.trees.get(tree_name) {
+        Some(tree_config) => tree_config,
+        None => {
+            println!("Unknown tree {}", tree_name);
+            process::exit(1);
+        },
+    };
 
     let filenames_file = &args[3];
 
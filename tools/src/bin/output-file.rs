@@ -8,12 +8,20 @@ use std::io::Read;
 use std::io::Write;
 use std::io::Seek;
 use std::path::Path;
+use std::process;
 use std::process::Command;
 
 extern crate tools;
+extern crate getopts;
+extern crate flate2;
+
+use getopts::Options;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
 use tools::find_source_file;
 use tools::file_format::analysis::{read_analysis, read_source, read_jumps};
-use tools::format::format_file_data;
+use tools::format::{format_file_data, log_url, raw_url};
 use tools::config;
 use tools::languages;
 use languages::FormatAs;
@@ -32,15 +40,57 @@ fn format_documentation(input_fname: &str, output_fname: &str) {
         .status();
 }
 
+// Writes `{fname}.gz` next to the output file so the web server can
+// send it to clients that accept gzip without compressing it again.
+fn write_gzip_sibling(fname: &str) {
+    let mut input = Vec::new();
+    File::open(fname).unwrap().read_to_end(&mut input).unwrap();
+
+    let output_file = File::create(format!("{}.gz", fname)).unwrap();
+    let mut encoder = GzEncoder::new(BufWriter::new(output_file), Compression::Best);
+    encoder.write_all(&input).unwrap();
+    encoder.finish().unwrap();
+}
+
+fn get_options_parser() -> Options {
+    let mut opts = Options::new();
+    opts.optflag("z", "gzip",
+                 "Also write a gzip-compressed copy of each output file.");
+    opts
+}
+
 fn main() {
     let args: Vec<_> = env::args().collect();
-    let (base_args, fname_args) = args.split_at(3);
 
-    let cfg = config::load(&base_args[1], false);
+    let opts = get_options_parser();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => { m },
+        Err(f) => { panic!(f.to_string()) },
+    };
+    let gzip = matches.opt_present("z");
+    let (base_args, fname_args) = matches.free.split_at(2);
+
+    // The blame map is read if the tree ignores some revisions, to skip
+    // the ones added since the blame repository was built. Building it
+    // walks the whole blame repository, so output.sh does that once
+    // before starting these processes, which then only map the file.
+    let cfg = match config::load(&base_args[0], true) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        },
+    };
     println!("Config file read");
 
-    let tree_name = &base_args[2];
-    let tree_config = cfg.trees.get(tree_name).unwrap();
+    let tree_name = &base_args[1];
+    let tree_config = match cfg.trees.get(tree_name) {
+        Some(tree_config) => tree_config,
+        None => {
+            println!("Unknown tree {}", tree_name);
+            process::exit(1);
+        },
+    };
 
     let jumps_fname = format!("{}/jumps", tree_config.paths.index_path);
     //let jumps : std::collections::HashMap<String, tools::analysis::Jump> = std::collections::HashMap::new();
@@ -73,7 +123,7 @@ fn main() {
             _ => {},
         };
 
-        let output_file = File::create(output_fname).unwrap();
+        let output_file = File::create(&output_fname).unwrap();
         let mut writer = BufWriter::new(output_file);
 
         let source_file = match File::open(source_fname.clone()) {
@@ -134,11 +184,11 @@ fn main() {
                     update_link_lineno: true,
                 }, PanelItem {
                     title: "Log".to_owned(),
-                    link: format!("https://hg.mozilla.org/mozilla-central/log/tip/{}", path),
+                    link: log_url(tree_name, &tree_config.urls, &oid.to_string(), path),
                     update_link_lineno: false,
                  }, PanelItem {
                     title: "Raw".to_owned(),
-                    link: format!("https://raw.githubusercontent.com/mozilla/gecko-dev/{}/{}", oid, path),
+                    link: raw_url(tree_name, &tree_config.urls, &oid.to_string(), path),
                     update_link_lineno: false,
                 }, PanelItem {
                     title: "Blame".to_owned(),
@@ -159,6 +209,12 @@ fn main() {
                          input,
                          &jumps,
                          &analysis,
+                         false,
                          &mut writer).unwrap();
+
+        if gzip {
+            writer.flush().unwrap();
+            write_gzip_sibling(&output_fname);
+        }
     }
 }
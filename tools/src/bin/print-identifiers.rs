@@ -1,11 +1,18 @@
 use std::env;
+use std::process;
 
 extern crate tools;
 use tools::config;
 use tools::file_format::identifiers::IdentMap;
 
 fn main() {
-    let cfg = config::load(&env::args().nth(1).unwrap(), false);
+    let cfg = match config::load(&env::args().nth(1).unwrap(), false) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            println!("{}", err);
+            process::exit(1);
+        },
+    };
     let id_map = IdentMap::load(&cfg);
     let ids = id_map.get(&env::args().nth(2).unwrap()).unwrap();
     let results = ids.lookup(&env::args().nth(3).unwrap(), false, true, 20);
@@ -1,118 +1,598 @@
 extern crate hyper;
 extern crate env_logger;
+extern crate flate2;
+extern crate brotli2;
+extern crate getopts;
+extern crate libc;
+extern crate rustc_serialize;
+extern crate chrono;
 extern crate tools;
 
 use std::sync::Mutex;
+use std::fs;
 use std::fs::File;
-use std::io::BufReader;
+use std::io;
 use std::io::Read;
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::env;
+use std::net::{SocketAddr, Shutdown};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
 
 use hyper::status::StatusCode;
 use hyper::method::Method;
 use hyper::server::{Request, Response};
-use hyper::header::ContentType;
+use hyper::header::{ContentType, ETag, EntityTag, IfNoneMatch, CacheControl, CacheDirective};
+use hyper::header::{AcceptEncoding, ContentEncoding, Encoding, Location};
+use hyper::header::{AcceptRanges, ByteRangeSpec, ContentRange, ContentRangeSpec, Range, RangeUnit};
 use hyper::mime::Mime;
 use hyper::uri;
+use hyper::net::{NetworkListener, NetworkStream};
+
+use getopts::Options;
+use rustc_serialize::json::Json;
+use chrono::{TimeZone, UTC};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use brotli2::write::BrotliEncoder;
 
 use tools::config;
 use tools::blame;
 use tools::format;
+use tools::counters;
+use tools::output::{self, F, Options as PageOptions};
 use tools::file_format::identifiers::IdentMap;
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Coding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Coding {
+    fn name(&self) -> &'static str {
+        match *self {
+            Coding::Identity => "identity",
+            Coding::Gzip => "gzip",
+            Coding::Brotli => "br",
+        }
+    }
+}
+
 struct WebRequest {
     path: String,
+
+    // Content codings accepted by the client, most preferred first.
+    accepted: Vec<Coding>,
 }
 
+#[derive(Clone)]
 struct WebResponse {
     status: StatusCode,
     content_type: String,
-    output: String,
+    output: Vec<u8>,
+
+    // Coding already applied to `output`.
+    coding: Coding,
+
+    // True if the response can never change (i.e., it was requested
+    // using a full revision hash).
+    immutable: bool,
+
+    // Modification time of the file being served, in seconds since
+    // the epoch.
+    last_modified: Option<i64>,
+
+    // Where to redirect to, for redirect responses.
+    location: Option<String>,
 }
 
 fn not_found() -> WebResponse {
     WebResponse {
         status: StatusCode::NotFound,
         content_type: "text/plain".to_owned(),
-        output: "Not found".to_owned()
+        output: b"Not found".to_vec(),
+        coding: Coding::Identity,
+        immutable: false,
+        last_modified: None,
+        location: None,
     }
 }
 
-fn handle_static(path: String, content_type: Option<&str>) -> WebResponse {
-    let source_file = match File::open(&path) {
-        Ok(f) => f,
-        Err(_) => {
-            return not_found();
-        },
+// Renders an HTML error page with the usual header and search box.
+fn error_page(cfg: &config::Config, tree_name: &str, status: StatusCode, message: &str) -> WebResponse {
+    // The search box needs a tree, so fall back to any tree we have.
+    let tree_name = if cfg.trees.contains_key(tree_name) {
+        tree_name
+    } else {
+        cfg.trees.keys().next().map(|t| &t[..]).unwrap_or("")
     };
-    let mut reader = BufReader::new(&source_file);
-    let mut input = String::new();
-    match reader.read_to_string(&mut input) {
-        Ok(_) => {},
-        Err(_) => {
-            return not_found();
+
+    let title = format!("{} - mozsearch", status);
+    let opt = PageOptions {
+        title: &title,
+        tree_name: tree_name,
+        include_date: false,
+        revision: None,
+    };
+
+    let mut writer = Vec::new();
+    output::generate_header(&opt, &mut writer).unwrap();
+    let f = F::Seq(vec![
+        F::T(format!("<h2>{}</h2>", status)),
+        F::T(format!("<p>{}</p>", output::entity_replace(message))),
+    ]);
+    output::generate_formatted(&mut writer, &f, 0).unwrap();
+    output::generate_footer(&opt, tree_name, "", &mut writer).unwrap();
+
+    WebResponse {
+        status: status,
+        content_type: "text/html".to_owned(),
+        output: writer,
+        coding: Coding::Identity,
+        immutable: false,
+        last_modified: None,
+        location: None,
+    }
+}
+
+fn redirect(location: String) -> WebResponse {
+    WebResponse {
+        status: StatusCode::Found,
+        content_type: "text/plain".to_owned(),
+        output: Vec::new(),
+        coding: Coding::Identity,
+        immutable: false,
+        last_modified: None,
+        location: Some(location),
+    }
+}
+
+fn internal_error(err: &str) -> WebResponse {
+    WebResponse {
+        status: StatusCode::InternalServerError,
+        content_type: "text/plain".to_owned(),
+        output: err.as_bytes().to_vec(),
+        coding: Coding::Identity,
+        immutable: false,
+        last_modified: None,
+        location: None,
+    }
+}
+
+// Abbreviated hashes and symbolic names like "HEAD" can resolve to
+// different commits over time, so only full hashes are immutable.
+fn is_full_sha(rev: &str) -> bool {
+    rev.len() == 40 && rev.chars().all(|c| c.is_digit(16))
+}
+
+// Each coding of a response is a different representation, so it
+// needs a distinct strong tag.
+fn entity_tag(output: &[u8], coding: Coding) -> EntityTag {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(output);
+    match coding {
+        Coding::Identity => EntityTag::strong(format!("{:016x}", hasher.finish())),
+        _ => EntityTag::strong(format!("{:016x}-{}", hasher.finish(), coding.name())),
+    }
+}
+
+fn etag_matches(if_none_match: &Option<IfNoneMatch>, etag: &EntityTag) -> bool {
+    match if_none_match {
+        &Some(IfNoneMatch::Any) => true,
+        &Some(IfNoneMatch::Items(ref tags)) => tags.iter().any(|t| t.weak_eq(etag)),
+        &None => false,
+    }
+}
+
+// Responses smaller than this aren't worth compressing.
+const MIN_COMPRESS_BYTES: usize = 1024;
+
+// Returns the codings the client accepts, most preferred first. An
+// entry naming a coding takes precedence over `*`, and codings the
+// client likes equally are tried in our own order of preference.
+fn accepted_codings(accept: Option<&AcceptEncoding>) -> Vec<Coding> {
+    let items = match accept {
+        Some(&AcceptEncoding(ref items)) => items,
+        None => return vec![],
+    };
+
+    let quality = |coding: Coding| {
+        let named = items.iter().find(|item| match item.item {
+            Encoding::Gzip => coding == Coding::Gzip,
+            Encoding::EncodingExt(ref ext) => ext == coding.name(),
+            _ => false,
+        });
+        let any = items.iter().find(|item| match item.item {
+            Encoding::EncodingExt(ref ext) => ext == "*",
+            _ => false,
+        });
+        named.or(any).map(|item| item.quality.0).unwrap_or(0)
+    };
+
+    let mut codings = [Coding::Brotli, Coding::Gzip].iter()
+        .map(|&coding| (quality(coding), coding))
+        .filter(|&(q, _)| q > 0)
+        .collect::<Vec<_>>();
+    // The sort is stable, so ties keep the order above.
+    codings.sort_by(|a, b| b.0.cmp(&a.0));
+    codings.into_iter().map(|(_, coding)| coding).collect()
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/") ||
+        content_type == "application/json" ||
+        content_type == "application/javascript"
+}
+
+fn compress(output: &[u8], coding: Coding) -> Vec<u8> {
+    match coding {
+        Coding::Identity => output.to_vec(),
+        Coding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+            encoder.write_all(output).unwrap();
+            encoder.finish().unwrap()
+        },
+        Coding::Brotli => {
+            let mut encoder = BrotliEncoder::new(Vec::new(), 6);
+            encoder.write_all(output).unwrap();
+            encoder.finish().unwrap()
+        },
+    }
+}
+
+// Compresses the response using the client's preferred coding, if
+// it's worthwhile.
+fn encode_response(response: WebResponse, accepted: &[Coding]) -> WebResponse {
+    if response.coding != Coding::Identity ||
+        response.output.len() < MIN_COMPRESS_BYTES ||
+        !is_compressible(&response.content_type)
+    {
+        return response;
+    }
+
+    match accepted.first() {
+        Some(&coding) => WebResponse {
+            output: compress(&response.output, coding),
+            coding: coding,
+            ..response
+        },
+        None => response,
+    }
+}
+
+// Keys include the generation the page was rendered from, so that a
+// request that was still rendering against the old generation during a
+// reload can't leave a stale page in the cache.
+fn cache_key(path: &str, coding: Coding, generation: usize) -> String {
+    format!("{} {} {}", generation, coding.name(), path)
+}
+
+// Maximum total size of the rendered pages kept in memory.
+const PAGE_CACHE_BYTES: usize = 256 * 1024 * 1024;
+
+// LRU cache of immutable responses, keyed by request path.
+struct PageCache {
+    max_bytes: usize,
+    cur_bytes: usize,
+    generation: u64,
+    entries: HashMap<String, (u64, WebResponse)>,
+    lru: BTreeMap<u64, String>,
+}
+
+impl PageCache {
+    fn new(max_bytes: usize) -> PageCache {
+        PageCache {
+            max_bytes: max_bytes,
+            cur_bytes: 0,
+            generation: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
         }
     }
 
-    let inferred_content_type = match Path::new(&path).extension() {
-        Some(ext) =>
-            match ext.to_str().unwrap() {
-                "css" => "text/css",
-                "js" => "text/javascript",
-                _ => "text/html",
+    fn get(&mut self, key: &str) -> Option<WebResponse> {
+        self.generation += 1;
+        match self.entries.get_mut(key) {
+            Some(&mut (ref mut generation, ref response)) => {
+                self.lru.remove(&*generation);
+                *generation = self.generation;
+                self.lru.insert(self.generation, key.to_owned());
+                Some(response.clone())
             },
-        None => "text/html"
+            None => None,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.cur_bytes = 0;
+        self.entries.clear();
+        self.lru.clear();
+    }
+
+    fn insert(&mut self, key: String, response: WebResponse) {
+        let size = response.output.len();
+        if size > self.max_bytes || self.entries.contains_key(&key) {
+            return;
+        }
+
+        while self.cur_bytes + size > self.max_bytes {
+            let oldest = match self.lru.keys().next() {
+                Some(generation) => *generation,
+                None => break,
+            };
+            let evicted = self.lru.remove(&oldest).unwrap();
+            let (_, evicted) = self.entries.remove(&evicted).unwrap();
+            self.cur_bytes -= evicted.output.len();
+        }
+
+        self.generation += 1;
+        self.cur_bytes += size;
+        self.lru.insert(self.generation, key.clone());
+        self.entries.insert(key, (self.generation, response));
+    }
+}
+
+// Resolves `path` relative to `root`. Returns None if the file
+// doesn't exist or if it is outside of `root` (because of `..`
+// components or symlinks).
+fn resolve_path(root: &str, path: &str) -> Option<PathBuf> {
+    let root = match fs::canonicalize(root) {
+        Ok(root) => root,
+        Err(_) => return None,
+    };
+    let resolved = match fs::canonicalize(root.join(path)) {
+        Ok(resolved) => resolved,
+        Err(_) => return None,
+    };
+    if resolved.starts_with(&root) {
+        Some(resolved)
+    } else {
+        None
+    }
+}
+
+fn content_type_for(path: &Path) -> &'static str {
+    let ext = match path.extension() {
+        Some(ext) => ext.to_str().unwrap_or(""),
+        None => "",
+    };
+    match ext {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "xml" => "application/xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "woff" => "application/font-woff",
+        "woff2" => "font/woff2",
+        "ttf" => "application/x-font-ttf",
+        "otf" => "application/x-font-opentype",
+        "eot" => "application/vnd.ms-fontobject",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+// Reads a file along with its modification time in seconds since the
+// epoch.
+fn read_file(path: &Path) -> Option<(Vec<u8>, Option<i64>)> {
+    let mut source_file = match File::open(path) {
+        Ok(f) => f,
+        Err(_) => return None,
+    };
+    let mut output = Vec::new();
+    match source_file.read_to_end(&mut output) {
+        Ok(_) => {},
+        Err(_) => return None,
+    }
+
+    let modified = source_file.metadata().and_then(|m| m.modified()).ok();
+    let last_modified = match modified.map(|t| t.duration_since(UNIX_EPOCH)) {
+        Some(Ok(d)) => Some(d.as_secs() as i64),
+        _ => None,
+    };
+
+    Some((output, last_modified))
+}
+
+// Serves the `.gz` sibling that output-file writes next to a
+// rendered file, if there is one.
+fn handle_precompressed(path: &Path, content_type: &str) -> Option<WebResponse> {
+    let mut gz_path = path.as_os_str().to_owned();
+    gz_path.push(".gz");
+    let (output, last_modified) = match read_file(Path::new(&gz_path)) {
+        Some(data) => data,
+        None => return None,
     };
+
+    Some(WebResponse {
+        status: StatusCode::Ok,
+        content_type: content_type.to_owned(),
+        output: output,
+        coding: Coding::Gzip,
+        immutable: false,
+        last_modified: last_modified,
+        location: None,
+    })
+}
+
+fn handle_static(path: &Path, content_type: Option<&str>) -> Option<WebResponse> {
+    let (output, last_modified) = match read_file(path) {
+        Some(data) => data,
+        None => return None,
+    };
+
     let content_type = match content_type {
         Some(ct) => ct,
-        None => inferred_content_type,
+        None => content_type_for(path),
     };
 
-    WebResponse { status: StatusCode::Ok, content_type: content_type.to_owned(), output: input }
+    Some(WebResponse {
+        status: StatusCode::Ok,
+        content_type: content_type.to_owned(),
+        output: output,
+        coding: Coding::Identity,
+        immutable: false,
+        last_modified: last_modified,
+        location: None,
+    })
+}
+
+// Raw files are served from the searchfox origin, so only trust the
+// extension for types that browsers can't run script from. Everything
+// else is plain text or an opaque download.
+fn raw_content_type(path: &Path, data: &[u8]) -> &'static str {
+    match content_type_for(path) {
+        "image/png" | "image/jpeg" | "image/gif" | "image/x-icon" | "application/pdf" =>
+            content_type_for(path),
+        _ => {
+            if data.contains(&0) || std::str::from_utf8(data).is_err() {
+                "application/octet-stream"
+            } else {
+                "text/plain; charset=utf-8"
+            }
+        }
+    }
+}
+
+fn raw_response(path: &Path, output: Vec<u8>, immutable: bool, last_modified: Option<i64>) -> WebResponse {
+    WebResponse {
+        status: StatusCode::Ok,
+        content_type: raw_content_type(path, &output).to_owned(),
+        output: output,
+        coding: Coding::Identity,
+        immutable: immutable,
+        last_modified: last_modified,
+        location: None,
+    }
+}
+
+fn http_date(secs: i64) -> String {
+    UTC.timestamp(secs, 0).format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_http_date(s: &str) -> Option<i64> {
+    UTC.datetime_from_str(s, "%a, %d %b %Y %H:%M:%S GMT").ok().map(|t| t.timestamp())
+}
+
+enum RangeSelection {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+// Picks the inclusive byte range to send for a `Range` request.
+// Requests for several ranges at once are rare enough that we just
+// send the whole thing.
+fn select_range(range: &Range, len: u64) -> RangeSelection {
+    let specs = match *range {
+        Range::Bytes(ref specs) if specs.len() == 1 => specs,
+        _ => return RangeSelection::Full,
+    };
+
+    let last = len.saturating_sub(1);
+    let (start, end) = match specs[0] {
+        ByteRangeSpec::FromTo(start, end) => (start, if end > last { last } else { end }),
+        ByteRangeSpec::AllFrom(start) => (start, last),
+        ByteRangeSpec::Last(n) => (len.saturating_sub(n), last),
+    };
+
+    if len == 0 || start >= len || start > end {
+        RangeSelection::Unsatisfiable
+    } else {
+        RangeSelection::Partial(start, end)
+    }
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').filter_map(|pair| {
+        let mut pieces = pair.splitn(2, '=');
+        match (pieces.next(), pieces.next()) {
+            (Some(key), Some(value)) if key == name => Some(value),
+            _ => None,
+        }
+    }).next()
 }
 
 fn handle(cfg: &config::Config, ident_map: &HashMap<String, IdentMap>, req: WebRequest) -> WebResponse {
-    let path = req.path.clone();
+    let (path, query) = match req.path.find('?') {
+        Some(i) => (&req.path[.. i], &req.path[i + 1 ..]),
+        None => (&req.path[..], ""),
+    };
     let path = path[1..].split('/').collect::<Vec<_>>();
 
     if path.len() > 0 && path[0] == "static" {
-        let path = cfg.mozsearch_path.clone() + &req.path;
-        return handle_static(path, None);
+        let static_root = format!("{}/static", cfg.mozsearch_path);
+        let static_path = path[1..].join("/");
+        let response = match resolve_path(&static_root, &static_path) {
+            Some(static_path) => handle_static(&static_path, None),
+            None => None,
+        };
+        return match response {
+            Some(response) => response,
+            None => error_page(cfg, "", StatusCode::NotFound, "File not found"),
+        };
     }
 
     if path.len() < 2 {
-        return not_found();
+        return error_page(cfg, "", StatusCode::NotFound, "Page not found");
     }
 
     let tree_name = &path[0];
     let kind = &path[1];
 
-    println!("DBG {:?} {} {}", path, tree_name, kind);
+
+    let tree_config = match cfg.trees.get(*tree_name) {
+        Some(tree_config) => tree_config,
+        None => return error_page(cfg, "", StatusCode::NotFound, "Unknown tree"),
+    };
 
     match &kind[..] {
         "rev" => {
             if path.len() < 3 {
-                return not_found();
+                return error_page(cfg, tree_name, StatusCode::NotFound, "No revision given");
             }
 
             let rev = &path[2];
             let path = path.clone().split_off(3);
             let path = path.join("/");
 
+            let color_by_age = query_param(query, "blame") == Some("age");
             let mut writer = Vec::new();
-            match format::format_path(cfg, &tree_name, &rev, &path, &mut writer) {
-                Ok(()) => {
-                    let output = String::from_utf8(writer).unwrap();
-                    WebResponse { status: StatusCode::Ok, content_type: "text/html".to_owned(), output: output }
-                },
-                Err(err) =>
+            match format::format_path(cfg, &tree_name, &rev, &path, color_by_age, &mut writer) {
+                Ok(complete) =>
                     WebResponse {
-                        status: StatusCode::InternalServerError,
-                        content_type: "text/plain".to_owned(),
-                        output: err.to_owned(),
-                    }
+                        status: StatusCode::Ok,
+                        content_type: "text/html".to_owned(),
+                        output: writer,
+                        coding: Coding::Identity,
+                        // Ages are relative to the current head, so colored
+                        // pages change as it moves.
+                        immutable: is_full_sha(rev) && complete && !color_by_age,
+                        last_modified: None,
+                        location: None,
+                    },
+                Err(err @ "Bad revision") | Err(err @ "File not found") =>
+                    error_page(cfg, tree_name, StatusCode::NotFound, err),
+                Err(err) => error_page(cfg, tree_name, StatusCode::InternalServerError, err),
             }
         },
 
@@ -120,55 +600,175 @@ fn handle(cfg: &config::Config, ident_map: &HashMap<String, IdentMap>, req: WebR
             let path = path.clone().split_off(2);
             let path = path.join("/");
 
-            let tree_config = cfg.trees.get(*tree_name).unwrap();
+            let file_root = format!("{}/file", tree_config.paths.index_path);
+            let path = match resolve_path(&file_root, &path) {
+                Some(path) => path,
+                None => return error_page(cfg, tree_name, StatusCode::NotFound, "File not found"),
+            };
+            if req.accepted.contains(&Coding::Gzip) {
+                if let Some(response) = handle_precompressed(&path, "text/html") {
+                    return response;
+                }
+            }
+            match handle_static(&path, Some("text/html")) {
+                Some(response) => response,
+                None => error_page(cfg, tree_name, StatusCode::NotFound, "File not found"),
+            }
+        },
+
+        "raw" => {
+            let path = path.clone().split_off(2);
+            let path = path.join("/");
+
+            // The files are usually a checkout, so only serve the ones
+            // the repository tracks, and never version control data.
+            let is_vcs_data = path.split('/').any(|name| name.starts_with(".git") || name.starts_with(".hg"));
+            if is_vcs_data || format::is_tracked_file(tree_config, &path) == Some(false) {
+                return error_page(cfg, tree_name, StatusCode::NotFound, "File not found");
+            }
 
-            let path = format!("{}/file/{}", tree_config.paths.index_path, path);
-            return handle_static(path, Some("text/html"));
+            let files_root = &tree_config.paths.files_path;
+            let path = match resolve_path(files_root, &path) {
+                Some(path) => path,
+                None => return error_page(cfg, tree_name, StatusCode::NotFound, "File not found"),
+            };
+            match read_file(&path) {
+                Some((output, last_modified)) => raw_response(&path, output, false, last_modified),
+                None => error_page(cfg, tree_name, StatusCode::NotFound, "File not found"),
+            }
+        },
+
+        "raw-rev" => {
+            if path.len() < 3 {
+                return error_page(cfg, tree_name, StatusCode::NotFound, "No revision given");
+            }
+
+            let rev = &path[2];
+            let path = path.clone().split_off(3);
+            let path = path.join("/");
+
+            match format::read_path_blob(cfg, &tree_name, &rev, &path) {
+                Ok(output) => raw_response(Path::new(&path), output, is_full_sha(rev), None),
+                Err(err @ "Bad revision") | Err(err @ "File not found") =>
+                    error_page(cfg, tree_name, StatusCode::NotFound, err),
+                Err(err) => error_page(cfg, tree_name, StatusCode::InternalServerError, err),
+            }
+        },
+
+        "log" => {
+            if path.len() < 3 {
+                return error_page(cfg, tree_name, StatusCode::NotFound, "No revision given");
+            }
+
+            let rev = &path[2];
+            let path = path.clone().split_off(3);
+            let path = path.join("/");
+
+            let page = query_param(query, "page").and_then(|p| p.parse().ok()).unwrap_or(1);
+
+            let mut writer = Vec::new();
+            match format::format_log(cfg, &tree_name, &rev, &path, page, &mut writer) {
+                Ok(()) =>
+                    WebResponse {
+                        status: StatusCode::Ok,
+                        content_type: "text/html".to_owned(),
+                        output: writer,
+                        coding: Coding::Identity,
+                        immutable: is_full_sha(rev),
+                        last_modified: None,
+                        location: None,
+                    },
+                Err(err @ "Bad revision") | Err(err @ "File not found") =>
+                    error_page(cfg, tree_name, StatusCode::NotFound, err),
+                Err(err) => error_page(cfg, tree_name, StatusCode::InternalServerError, err),
+            }
         },
 
         "diff" => {
             if path.len() < 3 {
-                return not_found();
+                return error_page(cfg, tree_name, StatusCode::NotFound, "No revision given");
             }
 
             let rev = &path[2];
             let path = path.clone().split_off(3);
             let path = path.join("/");
 
+            let side_by_side = query_param(query, "view") == Some("side-by-side");
+
             let mut writer = Vec::new();
-            match format::format_diff(cfg, &tree_name, &rev, &path, &mut writer) {
-                Ok(()) => {
-                    let output = String::from_utf8(writer).unwrap();
-                    WebResponse { status: StatusCode::Ok, content_type: "text/html".to_owned(), output: output }
-                },
-                Err(err) =>
+            match format::format_diff(cfg, &tree_name, &rev, &path, side_by_side, &mut writer) {
+                Ok(()) =>
+                    WebResponse {
+                        status: StatusCode::Ok,
+                        content_type: "text/html".to_owned(),
+                        output: writer,
+                        coding: Coding::Identity,
+                        immutable: is_full_sha(rev),
+                        last_modified: None,
+                        location: None,
+                    },
+                Err(err @ "Bad revision") | Err(err @ "File not found") =>
+                    error_page(cfg, tree_name, StatusCode::NotFound, err),
+                Err(err) => error_page(cfg, tree_name, StatusCode::InternalServerError, err),
+            }
+        },
+
+        "compare" => {
+            let revs = match path.get(2) {
+                Some(revs) => revs.split("..").collect::<Vec<_>>(),
+                None => vec![],
+            };
+            if revs.len() != 2 {
+                return error_page(cfg, tree_name, StatusCode::NotFound, "Expected two revisions, as rev1..rev2");
+            }
+
+            let path = path.clone().split_off(3);
+            let path = path.join("/");
+
+            let side_by_side = query_param(query, "view") == Some("side-by-side");
+
+            let mut writer = Vec::new();
+            match format::format_compare(cfg, &tree_name, revs[0], revs[1], &path, side_by_side, &mut writer) {
+                Ok(()) =>
                     WebResponse {
-                        status: StatusCode::InternalServerError,
-                        content_type: "text/plain".to_owned(),
-                        output: err.to_owned(),
-                    }
+                        status: StatusCode::Ok,
+                        content_type: "text/html".to_owned(),
+                        output: writer,
+                        coding: Coding::Identity,
+                        immutable: is_full_sha(revs[0]) && is_full_sha(revs[1]),
+                        last_modified: None,
+                        location: None,
+                    },
+                Err(err @ "Bad revision") | Err(err @ "File not found") | Err(err @ "Cannot diff binary file") =>
+                    error_page(cfg, tree_name, StatusCode::NotFound, err),
+                Err(err) => error_page(cfg, tree_name, StatusCode::InternalServerError, err),
             }
         },
 
         "commit" => {
             if path.len() < 3 {
-                return not_found();
+                return error_page(cfg, tree_name, StatusCode::NotFound, "No revision given");
             }
 
             let rev = &path[2];
 
+            let inline_diffs = query_param(query, "diffs") == Some("inline");
+
             let mut writer = Vec::new();
-            match format::format_commit(cfg, &tree_name, &rev, &mut writer) {
-                Ok(()) => {
-                    let output = String::from_utf8(writer).unwrap();
-                    WebResponse { status: StatusCode::Ok, content_type: "text/html".to_owned(), output: output }
-                },
-                Err(err) =>
+            match format::format_commit(cfg, &tree_name, &rev, inline_diffs, &mut writer) {
+                Ok(()) =>
                     WebResponse {
-                        status: StatusCode::InternalServerError,
-                        content_type: "text/plain".to_owned(),
-                        output: err.to_owned(),
-                    }
+                        status: StatusCode::Ok,
+                        content_type: "text/html".to_owned(),
+                        output: writer,
+                        coding: Coding::Identity,
+                        immutable: is_full_sha(rev),
+                        last_modified: None,
+                        location: None,
+                    },
+                Err(err @ "Bad revision") | Err(err @ "File not found") =>
+                    error_page(cfg, tree_name, StatusCode::NotFound, err),
+                Err(err) => error_page(cfg, tree_name, StatusCode::InternalServerError, err),
             }
         },
 
@@ -183,40 +783,512 @@ fn handle(cfg: &config::Config, ident_map: &HashMap<String, IdentMap>, req: WebR
                     WebResponse {
                         status: StatusCode::Ok,
                         content_type: "application/json".to_owned(),
-                        output: json
+                        output: json.into_bytes(),
+                        coding: Coding::Identity,
+                        immutable: is_full_sha(rev),
+                        last_modified: None,
+                        location: None,
                     },
-                Err(err) =>
+                Err(err) => internal_error(err),
+            }
+        },
+
+        "blame-prev" => {
+            if path.len() < 4 {
+                return error_page(cfg, tree_name, StatusCode::NotFound, "No revision given");
+            }
+
+            let rev = &path[2];
+            let path = path.clone().split_off(3);
+            let path = path.join("/");
+
+            let lineno = query_param(query, "line").and_then(|l| l.parse().ok()).unwrap_or(1);
+
+            match format::find_previous_line(cfg, &tree_name, &rev, &path, lineno) {
+                Ok((parent_rev, parent_path, parent_lineno)) =>
+                    redirect(format!("/{}/rev/{}/{}#l{}", tree_name, parent_rev, parent_path, parent_lineno)),
+                Err(err @ "File not found") | Err(err @ "File did not exist before this revision") |
+                Err(err @ "Revision has no parent") | Err(err @ "Bad revision") =>
+                    error_page(cfg, tree_name, StatusCode::NotFound, err),
+                Err(err) => error_page(cfg, tree_name, StatusCode::InternalServerError, err),
+            }
+        },
+
+        "blame" => {
+            if path.len() < 4 {
+                return not_found();
+            }
+
+            let rev = &path[2];
+            let path = path.clone().split_off(3);
+            let path = path.join("/");
+
+            match blame::get_blame_json(&cfg, tree_name, rev, &path) {
+                Ok(json) =>
                     WebResponse {
-                        status: StatusCode::InternalServerError,
-                        content_type: "text/plain".to_owned(),
-                        output: err.to_owned(),
-                    }
+                        status: StatusCode::Ok,
+                        content_type: "application/json".to_owned(),
+                        output: json.into_bytes(),
+                        coding: Coding::Identity,
+                        immutable: is_full_sha(rev),
+                        last_modified: None,
+                        location: None,
+                    },
+                Err("File not found") | Err("Bad revision") => not_found(),
+                Err(err) => internal_error(err),
             }
         },
 
         "complete" => {
-            let ids = ident_map.get(&tree_name.to_string()).unwrap();
+            if path.len() < 3 {
+                return not_found();
+            }
+
+            let ids = match ident_map.get(&tree_name.to_string()) {
+                Some(ids) => ids,
+                None => return not_found(),
+            };
             let json = ids.lookup_json(&path[2], false, false, 6);
             WebResponse {
                 status: StatusCode::Ok,
                 content_type: "application/json".to_owned(),
-                output: json
+                output: json.into_bytes(),
+                coding: Coding::Identity,
+                immutable: false,
+                last_modified: None,
+                location: None,
             }
         },
 
         _ => {
-            not_found()
+            error_page(cfg, tree_name, StatusCode::NotFound, "Page not found")
+        }
+    }
+}
+
+// hyper only knows about TCP, so Unix sockets need their own
+// listener and stream types.
+#[derive(Clone)]
+struct UnixHttpListener {
+    listener: Arc<UnixListener>,
+}
+
+struct UnixHttpStream(UnixStream);
+
+impl Clone for UnixHttpStream {
+    fn clone(&self) -> UnixHttpStream {
+        UnixHttpStream(self.0.try_clone().unwrap())
+    }
+}
+
+impl Read for UnixHttpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for UnixHttpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// Unix sockets don't have IP addresses, but hyper insists on one.
+fn unix_socket_addr() -> SocketAddr {
+    "127.0.0.1:0".parse().unwrap()
+}
+
+impl NetworkStream for UnixHttpStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(unix_socket_addr())
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.0.shutdown(how)
+    }
+}
+
+impl NetworkListener for UnixHttpListener {
+    type Stream = UnixHttpStream;
+
+    fn accept(&mut self) -> hyper::Result<UnixHttpStream> {
+        let (stream, _) = try!(self.listener.accept());
+        Ok(UnixHttpStream(stream))
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(unix_socket_addr())
+    }
+}
+
+// Prints a server event as `event key=value ...` so that it's easy
+// to grep and parse out of the server log.
+fn log_event(event: &str, fields: &[(&str, String)]) {
+    let fields = fields.iter().map(|&(k, ref v)| format!("{}={}", k, v)).collect::<Vec<_>>();
+    println!("{} {}", event, fields.join(" "));
+}
+
+// Everything loaded from the config file and the index. Reloading
+// replaces it as a whole.
+struct ServerData {
+    cfg: config::Config,
+    ident_map: HashMap<String, IdentMap>,
+}
+
+// Loading state of each tree, reported by /ready.
+type TreeStates = BTreeMap<String, &'static str>;
+
+fn set_tree_state(states: &Mutex<TreeStates>, tree_name: &str, state: &'static str) {
+    match states.lock() {
+        Ok(mut states) => states.insert(tree_name.to_owned(), state),
+        Err(poisoned) => poisoned.into_inner().insert(tree_name.to_owned(), state),
+    };
+}
+
+// Whichever tree was being loaded when loading failed is the culprit.
+// The other trees go back to the state they were in before, since the
+// old generation is still being served.
+fn mark_failed(states: &Mutex<TreeStates>, previous: TreeStates, loading_tree: &Mutex<Option<String>>) {
+    let culprit = match loading_tree.lock() {
+        Ok(tree) => tree.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    let mut states = match states.lock() {
+        Ok(states) => states,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    *states = previous;
+    if let Some(tree_name) = culprit {
+        states.insert(tree_name, "failed");
+    }
+}
+
+fn elapsed_ms(start: Instant) -> u64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1000000) as u64
+}
+
+// Trees are loaded one at a time so that /ready can report progress.
+fn load_server_data(config_path: &str,
+                    tree_names: &[String],
+                    states: &Mutex<TreeStates>,
+                    loading_tree: &Mutex<Option<String>>)
+                    -> Result<ServerData, config::ConfigError> {
+    let start = Instant::now();
+
+    let tree_names = if tree_names.is_empty() {
+        try!(config::tree_names(config_path))
+    } else {
+        tree_names.to_vec()
+    };
+    for tree_name in &tree_names {
+        set_tree_state(states, tree_name, "loading");
+    }
+
+    let mut cfg = try!(config::load_trees(config_path, true, Some(&[])));
+    let mut ident_map = HashMap::new();
+    for tree_name in &tree_names {
+        match loading_tree.lock() {
+            Ok(mut tree) => *tree = Some(tree_name.clone()),
+            Err(poisoned) => *poisoned.into_inner() = Some(tree_name.clone()),
+        }
+        let tree_start = Instant::now();
+        let tree_cfg = try!(config::load_trees(config_path, true, Some(&[tree_name.clone()][..])));
+        ident_map.extend(IdentMap::load(&tree_cfg));
+
+        for (tree_name, tree_config) in tree_cfg.trees {
+            // Counting the blame map reads it in, building or extending
+            // it if needed. That happens here on the loader thread so
+            // that the tree is only ready once the map is, rather than
+            // making the first request that needs it wait.
+            let (history, revisions) = match tree_config.git {
+                Some(ref git) => ("yes", git.blame_map.len()),
+                None => ("no", 0),
+            };
+            log_event("tree-loaded", &[
+                ("tree", tree_name.clone()),
+                ("history", history.to_owned()),
+                ("blame_revisions", revisions.to_string()),
+                ("load_ms", elapsed_ms(tree_start).to_string()),
+            ]);
+            set_tree_state(states, &tree_name, "loaded");
+            cfg.trees.insert(tree_name, tree_config);
         }
     }
+
+    log_event("loaded", &[
+        ("trees", cfg.trees.len().to_string()),
+        ("load_ms", elapsed_ms(start).to_string()),
+    ]);
+
+    Ok(ServerData { cfg: cfg, ident_map: ident_map })
+}
+
+// Requests hold on to the generation that was current when they
+// started, so a reload never pulls data out from under them. The
+// inner mutex is needed because git2 repositories aren't Sync.
+type Generation = Arc<Mutex<ServerData>>;
+
+static RELOAD_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn handle_sighup(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// Loads the first generation and then a new one every time SIGHUP
+// is received. Loading happens in the background so that the server
+// can answer /health and /ready in the meantime. If loading fails,
+// the old generation stays current.
+fn spawn_loader(config_path: String,
+                tree_names: Vec<String>,
+                current: Arc<Mutex<Option<Generation>>>,
+                states: Arc<Mutex<TreeStates>>,
+                page_cache: Arc<Mutex<PageCache>>) {
+    unsafe {
+        libc::signal(libc::SIGHUP, handle_sighup as *const () as libc::sighandler_t);
+    }
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+
+    thread::spawn(move || {
+        let mut generation = 0;
+        loop {
+            if !RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+
+            log_event("generation-loading", &[("generation", (generation + 1).to_string())]);
+            let config_path = config_path.clone();
+            let tree_names = tree_names.clone();
+            let loader_states = states.clone();
+            let previous_states = match states.lock() {
+                Ok(states) => states.clone(),
+                Err(poisoned) => poisoned.into_inner().clone(),
+            };
+            let loading_tree = Arc::new(Mutex::new(None));
+            let loader_tree = loading_tree.clone();
+            let loader = thread::spawn(move || {
+                load_server_data(&config_path, &tree_names, &loader_states, &loader_tree)
+            });
+            let data = match loader.join() {
+                Ok(Ok(data)) => data,
+                Ok(Err(err)) => {
+                    mark_failed(&states, previous_states, &loading_tree);
+                    log_event("generation-failed", &[
+                        ("generation", (generation + 1).to_string()),
+                        ("error", format!("{:?}", err.to_string())),
+                    ]);
+                    continue;
+                },
+                Err(_) => {
+                    mark_failed(&states, previous_states, &loading_tree);
+                    log_event("generation-failed", &[("generation", (generation + 1).to_string())]);
+                    continue;
+                },
+            };
+
+            generation += 1;
+            match current.lock() {
+                Ok(mut current) => *current = Some(Arc::new(Mutex::new(data))),
+                Err(poisoned) => *poisoned.into_inner() = Some(Arc::new(Mutex::new(data))),
+            }
+            match page_cache.lock() {
+                Ok(mut cache) => cache.clear(),
+                Err(poisoned) => poisoned.into_inner().clear(),
+            }
+            GENERATION.store(generation, Ordering::SeqCst);
+            log_event("generation-ready", &[("generation", generation.to_string())]);
+        }
+    });
+}
+
+static GENERATION: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Upper bounds of the request latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct RouteMetrics {
+    requests: u64,
+    buckets: [u64; 11],
+    seconds: f64,
+}
+
+#[derive(Default)]
+struct Metrics {
+    routes: BTreeMap<&'static str, RouteMetrics>,
+    cache_hits: u64,
+    cache_misses: u64,
+}
+
+impl Metrics {
+    fn record(&mut self, route: &'static str, seconds: f64) {
+        let route = self.routes.entry(route).or_insert(RouteMetrics::default());
+        route.requests += 1;
+        route.seconds += seconds;
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                route.buckets[i] += 1;
+            }
+        }
+    }
+
+    // Renders the metrics in the Prometheus text exposition format.
+    fn render(&self, cache_bytes: usize) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "# HELP searchfox_requests_total Requests handled, by route kind.").unwrap();
+        writeln!(out, "# TYPE searchfox_requests_total counter").unwrap();
+        for (route, m) in &self.routes {
+            writeln!(out, "searchfox_requests_total{{route=\"{}\"}} {}", route, m.requests).unwrap();
+        }
+
+        writeln!(out, "# HELP searchfox_request_duration_seconds Time taken to produce responses, by route kind.").unwrap();
+        writeln!(out, "# TYPE searchfox_request_duration_seconds histogram").unwrap();
+        for (route, m) in &self.routes {
+            for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+                writeln!(out, "searchfox_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                         route, bound, m.buckets[i]).unwrap();
+            }
+            writeln!(out, "searchfox_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+                     route, m.requests).unwrap();
+            writeln!(out, "searchfox_request_duration_seconds_sum{{route=\"{}\"}} {}", route, m.seconds).unwrap();
+            writeln!(out, "searchfox_request_duration_seconds_count{{route=\"{}\"}} {}", route, m.requests).unwrap();
+        }
+
+        writeln!(out, "# HELP searchfox_git_lookups_total Git objects looked up, by repository.").unwrap();
+        writeln!(out, "# TYPE searchfox_git_lookups_total counter").unwrap();
+        writeln!(out, "searchfox_git_lookups_total{{repo=\"source\"}} {}", counters::git_lookups()).unwrap();
+        writeln!(out, "searchfox_git_lookups_total{{repo=\"blame\"}} {}", counters::blame_lookups()).unwrap();
+
+        writeln!(out, "# HELP searchfox_page_cache_hits_total Immutable pages served from the page cache.").unwrap();
+        writeln!(out, "# TYPE searchfox_page_cache_hits_total counter").unwrap();
+        writeln!(out, "searchfox_page_cache_hits_total {}", self.cache_hits).unwrap();
+        writeln!(out, "# HELP searchfox_page_cache_misses_total Immutable pages that had to be rendered.").unwrap();
+        writeln!(out, "# TYPE searchfox_page_cache_misses_total counter").unwrap();
+        writeln!(out, "searchfox_page_cache_misses_total {}", self.cache_misses).unwrap();
+        writeln!(out, "# HELP searchfox_page_cache_bytes Size of the pages in the page cache.").unwrap();
+        writeln!(out, "# TYPE searchfox_page_cache_bytes gauge").unwrap();
+        writeln!(out, "searchfox_page_cache_bytes {}", cache_bytes).unwrap();
+
+        writeln!(out, "# HELP searchfox_generation Number of times the index data has been loaded.").unwrap();
+        writeln!(out, "# TYPE searchfox_generation gauge").unwrap();
+        writeln!(out, "searchfox_generation {}", GENERATION.load(Ordering::SeqCst)).unwrap();
+
+        out
+    }
+}
+
+fn route_kind(path: &str) -> &'static str {
+    let mut pieces = path[1..].split('/');
+    match pieces.next() {
+        Some("static") => return "static",
+        Some("health") | Some("ready") | Some("metrics") => return "status",
+        _ => {},
+    }
+    match pieces.next() {
+        Some("rev") => "rev",
+        Some("diff") => "diff",
+        Some("commit") => "commit",
+        Some("compare") => "compare",
+        Some("commit-info") => "commit-info",
+        Some("blame") => "blame",
+        Some("blame-prev") => "blame-prev",
+        Some("complete") => "complete",
+        Some("source") => "source",
+        Some("raw") => "raw",
+        Some("raw-rev") => "raw-rev",
+        Some("log") => "log",
+        _ => "other",
+    }
+}
+
+fn text_response(status: StatusCode, content_type: &str, output: String) -> WebResponse {
+    WebResponse {
+        status: status,
+        content_type: content_type.to_owned(),
+        output: output.into_bytes(),
+        coding: Coding::Identity,
+        immutable: false,
+        last_modified: None,
+        location: None,
+    }
+}
+
+// The server is ready once the first generation has been loaded.
+fn ready_response(ready: bool, states: &TreeStates) -> WebResponse {
+    let mut trees = BTreeMap::new();
+    for (tree_name, state) in states {
+        trees.insert(tree_name.clone(), Json::String(state.to_string()));
+    }
+    let mut obj = BTreeMap::new();
+    obj.insert("ready".to_owned(), Json::Boolean(ready));
+    obj.insert("trees".to_owned(), Json::Object(trees));
+
+    let status = if ready { StatusCode::Ok } else { StatusCode::ServiceUnavailable };
+    text_response(status, "application/json", Json::Object(obj).to_string())
+}
+
+fn get_options_parser() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("a", "address",
+                "Address to listen on (default 0.0.0.0).",
+                "ADDR");
+    opts.optopt("p", "port",
+                "Port to listen on (default 8001).",
+                "PORT");
+    opts.optopt("u", "unix-socket",
+                "Listen on a Unix domain socket instead of a TCP port.",
+                "PATH");
+    opts.optmulti("t", "tree",
+                  "Only serve the given tree. May be repeated; all trees are served by default.",
+                  "TREE");
+    opts
 }
 
 fn main() {
     env_logger::init().unwrap();
 
-    let cfg = config::load(&env::args().nth(1).unwrap(), true);
-    let ident_map = IdentMap::load(&cfg);
+    let args : Vec<String> = env::args().collect();
+    let opts = get_options_parser();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => { m },
+        Err(f) => { panic!(f.to_string()) },
+    };
+    if matches.free.len() != 1 {
+        let brief = format!("Usage: {} [options] config.json", args[0]);
+        print!("{}", opts.usage(&brief));
+        process::exit(1);
+    }
+
+    let address = matches.opt_str("a").unwrap_or("0.0.0.0".to_owned());
+    let port = match matches.opt_str("p") {
+        Some(port) => port.parse::<u16>().expect("Invalid port"),
+        None => 8001,
+    };
+    let unix_socket = matches.opt_str("u");
+    let tree_names = matches.opt_strs("t");
+
+    let config_path = matches.free[0].clone();
+
+    let current: Arc<Mutex<Option<Generation>>> = Arc::new(Mutex::new(None));
+    let states = Arc::new(Mutex::new(TreeStates::new()));
+    let page_cache = Arc::new(Mutex::new(PageCache::new(PAGE_CACHE_BYTES)));
+    let metrics = Mutex::new(Metrics::default());
 
-    let internal_data = Mutex::new((cfg, ident_map));
+    spawn_loader(config_path, tree_names, current.clone(), states.clone(), page_cache.clone());
 
     let handler = move |req: Request, mut res: Response| {
         if req.method != Method::Get {
@@ -232,21 +1304,230 @@ fn main() {
             _ => panic!("Unexpected URI"),
         };
 
-        let guard = match internal_data.lock() {
-            Ok(guard) => guard,
-            Err(poisoned) => poisoned.into_inner(),
+        let if_none_match = req.headers.get::<IfNoneMatch>().cloned();
+        let if_modified_since = match req.headers.get_raw("If-Modified-Since") {
+            Some(raw) if raw.len() == 1 => parse_http_date(&String::from_utf8_lossy(&raw[0])),
+            _ => None,
         };
-        let (ref cfg, ref ident_map) = *guard;
+        let range = req.headers.get::<Range>().cloned();
 
-        let response = handle(&cfg, &ident_map, WebRequest { path: path });
+        // Ranges refer to the uncompressed bytes, so don't compress
+        // responses to range requests.
+        let accepted = match range {
+            Some(_) => vec![],
+            None => accepted_codings(req.headers.get::<AcceptEncoding>()),
+        };
+        let preferred = accepted.first().cloned().unwrap_or(Coding::Identity);
+
+        let start = Instant::now();
+        let route = route_kind(&path);
+
+        let cache_get = |key: &str| {
+            match page_cache.lock() {
+                Ok(mut cache) => cache.get(key),
+                Err(poisoned) => poisoned.into_inner().get(key),
+            }
+        };
+        let cache_insert = |key: String, response: &WebResponse| {
+            match page_cache.lock() {
+                Ok(mut cache) => cache.insert(key, response.clone()),
+                Err(poisoned) => poisoned.into_inner().insert(key, response.clone()),
+            }
+        };
+        let with_metrics = |f: &Fn(&mut Metrics)| {
+            match metrics.lock() {
+                Ok(mut metrics) => f(&mut metrics),
+                Err(poisoned) => f(&mut poisoned.into_inner()),
+            }
+        };
+
+        // GENERATION is bumped after `current` is replaced, so reading it
+        // first means pages are never cached under a newer generation
+        // than the one they were rendered from.
+        let generation_number = GENERATION.load(Ordering::SeqCst);
+        let generation = match current.lock() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+
+        let response = match &path[..] {
+            "/health" => text_response(StatusCode::Ok, "text/plain", "OK".to_owned()),
+
+            "/ready" => {
+                match states.lock() {
+                    Ok(states) => ready_response(generation.is_some(), &states),
+                    Err(poisoned) => ready_response(generation.is_some(), &poisoned.into_inner()),
+                }
+            },
+
+            "/metrics" => {
+                let cache_bytes = match page_cache.lock() {
+                    Ok(cache) => cache.cur_bytes,
+                    Err(poisoned) => poisoned.into_inner().cur_bytes,
+                };
+                let output = match metrics.lock() {
+                    Ok(metrics) => metrics.render(cache_bytes),
+                    Err(poisoned) => poisoned.into_inner().render(cache_bytes),
+                };
+                text_response(StatusCode::Ok, "text/plain; version=0.0.4", output)
+            },
+
+            _ => {
+                let cached = match cache_get(&cache_key(&path, preferred, generation_number)) {
+                    Some(response) => Some(response),
+                    None => cache_get(&cache_key(&path, Coding::Identity, generation_number)),
+                };
+
+                let response = match (cached, generation) {
+                    (Some(response), _) => {
+                        with_metrics(&|m| m.cache_hits += 1);
+                        response
+                    },
+                    (None, None) => {
+                        text_response(StatusCode::ServiceUnavailable, "text/plain",
+                                      "Server is still loading".to_owned())
+                    },
+                    (None, Some(generation)) => {
+                        let guard = match generation.lock() {
+                            Ok(guard) => guard,
+                            Err(poisoned) => poisoned.into_inner(),
+                        };
+                        let ServerData { ref cfg, ref ident_map } = *guard;
+
+                        let response = handle(&cfg, &ident_map, WebRequest { path: path.clone(), accepted: accepted.clone() });
+                        if response.immutable && response.status == StatusCode::Ok {
+                            with_metrics(&|m| m.cache_misses += 1);
+                            cache_insert(cache_key(&path, response.coding, generation_number), &response);
+                        }
+                        response
+                    },
+                };
+
+                let coding = response.coding;
+                let response = encode_response(response, &accepted);
+                if response.immutable && response.status == StatusCode::Ok && response.coding != coding {
+                    cache_insert(cache_key(&path, response.coding, generation_number), &response);
+                }
+                response
+            },
+        };
+
+        let elapsed = start.elapsed();
+        let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        with_metrics(&|m| m.record(route, seconds));
 
         *res.status_mut() = response.status;
-        let output = response.output.into_bytes();
         let mime: Mime = response.content_type.parse().unwrap();
         res.headers_mut().set(ContentType(mime));
-        res.send(&output).unwrap();
+
+        if let Some(ref location) = response.location {
+            res.headers_mut().set(Location(location.clone()));
+        }
+
+        if is_compressible(&response.content_type) {
+            res.headers_mut().set_raw("Vary", vec![b"Accept-Encoding".to_vec()]);
+        }
+        match response.coding {
+            Coding::Identity => {},
+            Coding::Gzip => res.headers_mut().set(ContentEncoding(vec![Encoding::Gzip])),
+            Coding::Brotli => res.headers_mut().set(ContentEncoding(vec![Encoding::EncodingExt("br".to_owned())])),
+        }
+
+        if response.status == StatusCode::Ok {
+            let etag = entity_tag(&response.output, response.coding);
+            if response.immutable {
+                res.headers_mut().set(CacheControl(vec![
+                    CacheDirective::Public,
+                    CacheDirective::MaxAge(365 * 24 * 60 * 60),
+                    CacheDirective::Extension("immutable".to_owned(), None),
+                ]));
+            }
+            // If-None-Match takes precedence over If-Modified-Since.
+            let not_modified = match (&if_none_match, response.last_modified, if_modified_since) {
+                (&Some(_), _, _) => etag_matches(&if_none_match, &etag),
+                (&None, Some(modified), Some(since)) => modified <= since,
+                _ => false,
+            };
+            res.headers_mut().set(ETag(etag));
+            if let Some(modified) = response.last_modified {
+                res.headers_mut().set_raw("Last-Modified", vec![http_date(modified).into_bytes()]);
+            }
+            if not_modified {
+                *res.status_mut() = StatusCode::NotModified;
+                res.send(b"").unwrap();
+                return;
+            }
+
+            if response.coding == Coding::Identity {
+                res.headers_mut().set(AcceptRanges(vec![RangeUnit::Bytes]));
+
+                let len = response.output.len() as u64;
+                let selection = match range {
+                    Some(ref range) => select_range(range, len),
+                    None => RangeSelection::Full,
+                };
+                match selection {
+                    RangeSelection::Full => {},
+                    RangeSelection::Partial(start, end) => {
+                        *res.status_mut() = StatusCode::PartialContent;
+                        res.headers_mut().set(ContentRange(ContentRangeSpec::Bytes {
+                            range: Some((start, end)),
+                            instance_length: Some(len),
+                        }));
+                        res.send(&response.output[start as usize .. (end + 1) as usize]).unwrap();
+                        return;
+                    },
+                    RangeSelection::Unsatisfiable => {
+                        *res.status_mut() = StatusCode::RangeNotSatisfiable;
+                        res.headers_mut().set(ContentRange(ContentRangeSpec::Bytes {
+                            range: None,
+                            instance_length: Some(len),
+                        }));
+                        res.send(b"").unwrap();
+                        return;
+                    },
+                }
+            }
+        }
+
+        res.send(&response.output).unwrap();
+    };
+
+    let _listening = match unix_socket {
+        Some(socket_path) => {
+            // Remove any socket left behind by a previous run.
+            let _ = fs::remove_file(&socket_path);
+            let listener = UnixHttpListener {
+                listener: Arc::new(UnixListener::bind(&socket_path).unwrap()),
+            };
+            log_event("listening", &[("unix_socket", socket_path.clone())]);
+            hyper::Server::new(listener).handle(handler).unwrap()
+        },
+        None => {
+            let server = hyper::Server::http((&address[..], port)).unwrap();
+            log_event("listening", &[("address", address.clone()), ("port", port.to_string())]);
+            server.handle(handler).unwrap()
+        },
     };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::Header;
 
-    println!("On 8001");
-    let _listening = hyper::Server::http("0.0.0.0:8001").unwrap().handle(handler);
+    fn codings(accept: &str) -> Vec<Coding> {
+        let header = AcceptEncoding::parse_header(&[accept.as_bytes().to_vec()]).unwrap();
+        accepted_codings(Some(&header))
+    }
+
+    #[test]
+    fn test_accepted_codings() {
+        assert_eq!(codings("gzip, deflate, br"), vec![Coding::Brotli, Coding::Gzip]);
+        assert_eq!(codings("gzip;q=1, br;q=0.1"), vec![Coding::Gzip, Coding::Brotli]);
+        assert_eq!(codings("br;q=0, *"), vec![Coding::Gzip]);
+        assert_eq!(codings("*;q=0.5, gzip"), vec![Coding::Gzip, Coding::Brotli]);
+        assert_eq!(codings("identity"), vec![]);
+        assert_eq!(accepted_codings(None), vec![]);
+    }
 }
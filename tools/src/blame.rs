@@ -1,56 +1,138 @@
 use config;
+use counters;
+use output::entity_replace;
 
-use std::collections::BTreeMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
 use rustc_serialize::json::Json;
-use regex::Regex;
 use git2;
 
 use chrono::naive::datetime::NaiveDateTime;
 use chrono::offset::fixed::FixedOffset;
 use chrono::datetime::DateTime;
 
-fn linkify_commit_header(s: &str) -> String {
-    lazy_static! {
-        static ref BUG_NUMBER_REGEX: Regex = {
-            Regex::new(r"\b(?P<bugno>[1-9][0-9]{4,9})\b").unwrap()
-        };
-        static ref SERVO_PR_REGEX: Regex = {
-            Regex::new(r"#(?P<prno>[1-9][0-9]*)\b").unwrap()
-        };
+// Applies the tree's link rules to a commit message header. Rules are
+// tried in order, and a match that overlaps one from an earlier rule
+// is skipped. Returns HTML.
+fn linkify_commit_header(s: &str, rules: &[config::CommitLinkRule]) -> String {
+    let mut links: Vec<(usize, usize, String)> = Vec::new();
+    for rule in rules {
+        if let Some(ref prefix) = rule.prefix {
+            if !s.starts_with(prefix.as_str()) {
+                continue;
+            }
+        }
+
+        for caps in rule.regex.captures_iter(s) {
+            let (start, end) = caps.pos(0).unwrap();
+            if start == end || links.iter().any(|&(s, e, _)| start < e && s < end) {
+                continue;
+            }
+
+            let id = caps.name("id").unwrap_or(caps.at(0).unwrap());
+            let mut vars = vec![("id", id)];
+            for (name, value) in caps.iter_named() {
+                if let Some(value) = value {
+                    vars.push((name, value));
+                }
+            }
+            let url = config::expand_url(&rule.url, &vars);
+            links.push((start, end, url));
+        }
     }
-    if s.starts_with("servo: ") {
-        SERVO_PR_REGEX.replace_all(s, "#<a href=\"https://github.com/servo/servo/pull/$prno\">$prno</a>")
-    } else {
-        BUG_NUMBER_REGEX.replace_all(s, "<a href=\"https://bugzilla.mozilla.org/show_bug.cgi?id=$bugno\">$bugno</a>")
+    links.sort_by_key(|&(start, _, _)| start);
+
+    let mut result = String::new();
+    let mut last = 0;
+    for (start, end, url) in links {
+        result.push_str(&entity_replace(&s[last .. start]));
+        result.push_str(&format!("<a href=\"{}\">{}</a>",
+                                 entity_replace(&url),
+                                 entity_replace(&s[start .. end])));
+        last = end;
     }
+    result.push_str(&entity_replace(&s[last ..]));
+    result
+}
+
+#[cfg(test)]
+fn test_rules() -> Vec<config::CommitLinkRule> {
+    vec![config::CommitLinkRule {
+        regex: ::regex::Regex::new(r"#(?P<id>[1-9][0-9]*)\b").unwrap(),
+        url: "https://github.com/servo/servo/pull/{id}".to_owned(),
+        prefix: Some("servo: ".to_owned()),
+    }, config::CommitLinkRule {
+        regex: ::regex::Regex::new(r"\b(?P<id>[1-9][0-9]{4,9})\b").unwrap(),
+        url: "https://bugzilla.mozilla.org/show_bug.cgi?id={id}".to_owned(),
+        prefix: None,
+    }]
 }
 
 #[test]
 fn test_linkify_servo_pr() {
     let linkified =
-        linkify_commit_header("servo: Merge #1234 - stylo: Report a specific error for invalid CSS color values (from jdm:valueerr); r=heycam");
+        linkify_commit_header("servo: Merge #1234 - stylo: Report a specific error for invalid CSS color values (from jdm:valueerr); r=heycam", &test_rules());
     assert!(linkified.contains("github.com"), "{:?}", linkified);
 }
 
-pub fn commit_header(commit: &git2::Commit) -> Result<(String, String), &'static str> {
-    fn entity_replace(s: &str) -> String {
-        s.replace("&", "&amp;").replace("<", "&lt;")
-    }
+#[test]
+fn test_linkify_prefix() {
+    let linkified = linkify_commit_header("Bug 123456 - Fix #1234", &test_rules());
+    assert_eq!(linkified, "Bug <a href=\"https://bugzilla.mozilla.org/show_bug.cgi?id=123456\">123456</a> - Fix #1234");
+}
+
+#[test]
+fn test_linkify_no_overlap() {
+    // The PR rule claims "#12345" first, so the bug rule can't link
+    // the number inside it.
+    let linkified = linkify_commit_header("servo: Merge #12345", &test_rules());
+    assert_eq!(linkified, "servo: Merge <a href=\"https://github.com/servo/servo/pull/12345\">#12345</a>");
+}
+
+#[test]
+fn test_linkify_escaping() {
+    let rules = vec![config::CommitLinkRule {
+        regex: ::regex::Regex::new(r"(?P<project>[A-Z]+)-(?P<num>[0-9]+)").unwrap(),
+        url: "https://jira.example.com/browse?project={project}&id={num}".to_owned(),
+        prefix: None,
+    }];
+    let linkified = linkify_commit_header("<b> & ABC-12", &rules);
+    assert_eq!(linkified, "&lt;b> &amp; <a href=\"https://jira.example.com/browse?project=ABC&amp;id=12\">ABC-12</a>");
+}
 
+#[test]
+fn test_blame_line() {
+    let line = BlameLine::parse("abc,def,123:dom/a.cpp:12:Some: Author").unwrap();
+    assert_eq!(line.rev, "abc");
+    assert_eq!(line.skipped, vec!["def".to_owned(), "123".to_owned()]);
+    assert_eq!(line.path, "dom/a.cpp");
+    assert_eq!(line.lineno, 12);
+    assert_eq!(line.author, "Some: Author");
+    assert_eq!(line.to_string(), "abc,def,123:dom/a.cpp:12:Some: Author");
+
+    assert!(BlameLine::parse("abc:%:x:A").is_none());
+}
+
+pub fn commit_header(commit: &git2::Commit, rules: &[config::CommitLinkRule]) -> Result<(String, String), &'static str> {
     let msg = try!(commit.message().ok_or("Invalid message"));
     let mut iter = msg.split('\n');
     let header = iter.next().unwrap();
     let remainder = iter.collect::<Vec<_>>().join("\n");
-    let header = linkify_commit_header(&entity_replace(header));
+    let header = linkify_commit_header(header, rules);
     Ok((header, entity_replace(&remainder)))
 }
 
 pub fn get_commit_info(cfg: &config::Config, tree_name: &str, rev: &str) -> Result<String, &'static str> {
     let tree_config = try!(cfg.trees.get(tree_name).ok_or("Invalid tree"));
     let git = try!(config::get_git(tree_config));
+    counters::count_git_lookup();
     let commit_obj = try!(git.repo.revparse_single(rev).map_err(|_| "Bad revision"));
     let commit = try!(commit_obj.as_commit().ok_or("Bad revision"));
-    let (msg, _) = try!(commit_header(&commit));
+    let (msg, _) = try!(commit_header(&commit, &tree_config.commit_links));
 
     let naive_t = NaiveDateTime::from_timestamp(commit.time().seconds(), 0);
     let tz = FixedOffset::east(commit.time().offset_minutes() * 60);
@@ -76,3 +158,309 @@ pub fn get_commit_info(cfg: &config::Config, tree_name: &str, rev: &str) -> Resu
 
     Ok(json.to_string())
 }
+
+// Returns blame for `path` at `rev` as JSON. Each line gives the
+// revision that introduced it, the path and line number it had in that
+// revision, and any ignored revisions that were skipped. `commits` has
+// the author, date and summary of each of those revisions.
+pub fn get_blame_json(cfg: &config::Config, tree_name: &str, rev: &str, path: &str) -> Result<String, &'static str> {
+    let tree_config = try!(cfg.trees.get(tree_name).ok_or("Invalid tree"));
+    let git = try!(config::get_git(tree_config));
+    counters::count_git_lookup();
+    let commit_obj = try!(git.repo.revparse_single(rev).map_err(|_| "Bad revision"));
+    let commit = try!(commit_obj.as_commit().ok_or("Bad revision"));
+
+    let blame_oid = try!(git.blame_map.get(&commit.id()).ok_or("Unable to find blame for revision"));
+    let blame_commit = try!(git.blame_repo.find_commit(blame_oid).map_err(|_| "Blame is not available"));
+    let blame_tree = try!(blame_commit.tree().map_err(|_| "Bad revision"));
+    let blame_entry = try!(blame_tree.get_path(Path::new(path)).map_err(|_| "File not found"));
+    counters::count_blame_lookup();
+    let blame_obj = try!(blame_entry.to_object(&git.blame_repo).map_err(|_| "File not found"));
+    let blame_blob = try!(blame_obj.as_blob().ok_or("Invalid path; expected file"));
+    let blame = String::from_utf8_lossy(blame_blob.content());
+
+    let mut blame_lines = Vec::new();
+    for line in blame.lines() {
+        blame_lines.push(try!(BlameLine::parse(line).ok_or("Bad blame data")));
+    }
+    skip_ignored_revs(git, path, &mut blame_lines);
+
+    let mut lines = Vec::new();
+    let mut commits = BTreeMap::new();
+    for blame_line in &blame_lines {
+        let line_path = if blame_line.path == "%" { path } else { &blame_line.path };
+
+        let mut line = BTreeMap::new();
+        line.insert("rev".to_owned(), Json::String(blame_line.rev.clone()));
+        line.insert("path".to_owned(), Json::String(line_path.to_owned()));
+        line.insert("line".to_owned(), Json::U64(blame_line.lineno as u64));
+        if !blame_line.skipped.is_empty() {
+            let skipped = blame_line.skipped.iter().map(|rev| Json::String(rev.clone())).collect();
+            line.insert("skipped".to_owned(), Json::Array(skipped));
+        }
+        lines.push(Json::Object(line));
+
+        for rev in Some(&blame_line.rev).into_iter().chain(blame_line.skipped.iter()) {
+            if commits.contains_key(rev) {
+                continue;
+            }
+            let oid = try!(git2::Oid::from_str(rev).map_err(|_| "Bad blame data"));
+            counters::count_git_lookup();
+            let line_commit = try!(git.repo.find_commit(oid).map_err(|_| "Bad blame data"));
+            commits.insert(rev.clone(), commit_json(&line_commit));
+        }
+    }
+
+    let mut obj = BTreeMap::new();
+    obj.insert("rev".to_owned(), Json::String(commit.id().to_string()));
+    obj.insert("path".to_owned(), Json::String(path.to_owned()));
+    obj.insert("lines".to_owned(), Json::Array(lines));
+    obj.insert("commits".to_owned(), Json::Object(commits));
+
+    Ok(Json::Object(obj).to_string())
+}
+
+fn commit_json(commit: &git2::Commit) -> Json {
+    let naive_t = NaiveDateTime::from_timestamp(commit.time().seconds(), 0);
+    let tz = FixedOffset::east(commit.time().offset_minutes() * 60);
+    let t : DateTime<FixedOffset> = DateTime::from_utc(naive_t, tz);
+
+    let sig = commit.author();
+    let summary = commit.message().and_then(|msg| msg.lines().next()).unwrap_or("");
+
+    let mut obj = BTreeMap::new();
+    obj.insert("author".to_owned(), Json::String(String::from_utf8_lossy(sig.name_bytes()).into_owned()));
+    obj.insert("email".to_owned(), Json::String(String::from_utf8_lossy(sig.email_bytes()).into_owned()));
+    obj.insert("date".to_owned(), Json::String(t.to_rfc3339()));
+    obj.insert("summary".to_owned(), Json::String(summary.to_owned()));
+    Json::Object(obj)
+}
+
+// One line of a file in the blame repository, which looks like
+// `rev:path:lineno:author`. `path` is `%` if it's the same as the path
+// of the file. If ignored revisions changed the line after `rev`, they
+// follow it, oldest first: `rev,ignored-rev,...:path:lineno:author`.
+pub struct BlameLine {
+    pub rev: String,
+    pub skipped: Vec<String>,
+    pub path: String,
+    pub lineno: usize,
+    pub author: String,
+}
+
+impl BlameLine {
+    pub fn parse(line: &str) -> Option<BlameLine> {
+        let pieces = line.splitn(4, ':').collect::<Vec<_>>();
+        if pieces.len() < 3 {
+            return None;
+        }
+        let mut revs = pieces[0].split(',').map(|rev| rev.to_owned());
+        let lineno = match pieces[2].parse() {
+            Ok(lineno) => lineno,
+            Err(_) => return None,
+        };
+        Some(BlameLine {
+            rev: revs.next().unwrap(),
+            skipped: revs.collect(),
+            path: pieces[1].to_owned(),
+            lineno: lineno,
+            author: pieces.get(3).map(|author| author.to_string()).unwrap_or(String::new()),
+        })
+    }
+}
+
+impl fmt::Display for BlameLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.rev));
+        for rev in &self.skipped {
+            try!(write!(f, ",{}", rev));
+        }
+        write!(f, ":{}:{}:{}", self.path, self.lineno, self.author)
+    }
+}
+
+// Reads a list of revisions for blame to skip, one per line. Blank
+// lines and lines starting with `#` are ignored, as in git's
+// `blame.ignoreRevsFile`.
+pub fn read_ignore_revs(path: &str) -> Result<HashSet<git2::Oid>, String> {
+    let file = try!(File::open(path).map_err(|err| format!("Unable to open {}: {}", path, err)));
+    let mut revs = HashSet::new();
+    for line in BufReader::new(file).lines() {
+        let line = try!(line.map_err(|err| format!("Unable to read {}: {}", path, err)));
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let oid = try!(git2::Oid::from_str(line).map_err(|_| format!("Bad revision in {}: {}", path, line)));
+        revs.insert(oid);
+    }
+    Ok(revs)
+}
+
+fn count_lines(data: &[u8]) -> usize {
+    match data.last() {
+        None => 0,
+        Some(&b'\n') => data.iter().filter(|&&b| b == b'\n').count(),
+        Some(_) => data.iter().filter(|&&b| b == b'\n').count() + 1,
+    }
+}
+
+// Returns (new line, old line) pairs, counting from 0, for the lines
+// of `new_blob` that correspond to lines of `old_blob`. Unchanged lines
+// always correspond. If `pair_changes` is set, each added line also
+// corresponds to the line removed at the same place in its hunk (or to
+// the last one, if fewer lines were removed), which is a good guess for
+// reformatting changes.
+pub fn map_lines(old_blob: &git2::Blob, new_blob: &git2::Blob, pair_changes: bool) -> Vec<(usize, usize)> {
+    if old_blob.is_binary() || new_blob.is_binary() {
+        return vec![];
+    }
+
+    let old_count = count_lines(old_blob.content());
+    let new_count = count_lines(new_blob.content());
+    if old_blob.id() == new_blob.id() {
+        return (0 .. new_count).map(|i| (i, i)).collect();
+    }
+
+    // With enough context every unchanged line shows up in the patch.
+    let mut opts = git2::DiffOptions::new();
+    opts.patience(true);
+    opts.context_lines(cmp::max(old_count, new_count) as u32);
+    let patch = match git2::Patch::from_blobs(old_blob, None, new_blob, None, Some(&mut opts)) {
+        Ok(patch) => patch,
+        Err(_) => return vec![],
+    };
+
+    fn pair(mapping: &mut Vec<(usize, usize)>, removed: &mut Vec<usize>, added: &mut Vec<usize>) {
+        if let Some(&last) = removed.last() {
+            for (i, new_lineno) in added.iter().enumerate() {
+                mapping.push((*new_lineno, *removed.get(i).unwrap_or(&last)));
+            }
+        }
+        removed.clear();
+        added.clear();
+    }
+
+    let mut mapping = vec![];
+    let mut removed = vec![];
+    let mut added = vec![];
+    for hunk in 0 .. patch.num_hunks() {
+        let num_lines = patch.num_lines_in_hunk(hunk).unwrap_or(0);
+        for i in 0 .. num_lines {
+            let line = match patch.line_in_hunk(hunk, i) {
+                Ok(line) => line,
+                Err(_) => continue,
+            };
+            match (line.origin(), line.new_lineno(), line.old_lineno()) {
+                (' ', Some(new_lineno), Some(old_lineno)) => {
+                    pair(&mut mapping, &mut removed, &mut added);
+                    mapping.push((new_lineno as usize - 1, old_lineno as usize - 1));
+                },
+                ('+', Some(new_lineno), _) if pair_changes => added.push(new_lineno as usize - 1),
+                ('-', _, Some(old_lineno)) if pair_changes => removed.push(old_lineno as usize - 1),
+                _ => {},
+            }
+        }
+        pair(&mut mapping, &mut removed, &mut added);
+    }
+    mapping.sort();
+    mapping
+}
+
+// Stop following a line back through ignored revisions after this many.
+const MAX_SKIPPED_REVS: usize = 10;
+
+struct IgnoredRevFile {
+    // Maps lines of the file in the ignored revision to lines of the
+    // file in its first parent.
+    mapping: HashMap<usize, usize>,
+    parent_blame: Vec<String>,
+}
+
+fn ignored_rev_file(git: &config::GitData, rev: git2::Oid, path: &str) -> Option<IgnoredRevFile> {
+    fn blob_at<'r>(repo: &'r git2::Repository, commit: &git2::Commit, path: &str) -> Option<git2::Blob<'r>> {
+        let tree = match commit.tree() {
+            Ok(tree) => tree,
+            Err(_) => return None,
+        };
+        match tree.get_path(Path::new(path)) {
+            Ok(entry) => repo.find_blob(entry.id()).ok(),
+            Err(_) => None,
+        }
+    }
+
+    counters::count_git_lookup();
+    let commit = match git.repo.find_commit(rev) {
+        Ok(commit) => commit,
+        Err(_) => return None,
+    };
+    let parent = match commit.parent(0) {
+        Ok(parent) => parent,
+        Err(_) => return None,
+    };
+    let blame_commit = match git.blame_map.get(&parent.id()).and_then(|oid| git.blame_repo.find_commit(oid).ok()) {
+        Some(blame_commit) => blame_commit,
+        None => return None,
+    };
+
+    let (new_blob, old_blob) = match (blob_at(&git.repo, &commit, path), blob_at(&git.repo, &parent, path)) {
+        (Some(new_blob), Some(old_blob)) => (new_blob, old_blob),
+        _ => return None,
+    };
+    counters::count_blame_lookup();
+    let parent_blame = match blob_at(&git.blame_repo, &blame_commit, path) {
+        Some(blob) => String::from_utf8_lossy(blob.content()).lines().map(|line| line.to_owned()).collect(),
+        None => return None,
+    };
+
+    Some(IgnoredRevFile {
+        mapping: map_lines(&old_blob, &new_blob, true).into_iter().collect(),
+        parent_blame: parent_blame,
+    })
+}
+
+// Blame repositories built before a revision was added to the tree's
+// ignore list still attribute lines to it. This follows those lines
+// back to the revision's first parent, as the blame builder would have.
+pub fn skip_ignored_revs(git: &config::GitData, path: &str, lines: &mut Vec<BlameLine>) {
+    if git.ignore_revs.is_empty() {
+        return;
+    }
+
+    let mut files: HashMap<(String, String), Option<IgnoredRevFile>> = HashMap::new();
+    for line in lines.iter_mut() {
+        for _ in 0 .. MAX_SKIPPED_REVS {
+            let rev = match git2::Oid::from_str(&line.rev) {
+                Ok(rev) if git.ignore_revs.contains(&rev) => rev,
+                _ => break,
+            };
+            let line_path = if line.path == "%" { path.to_owned() } else { line.path.clone() };
+
+            let key = (line.rev.clone(), line_path.clone());
+            if !files.contains_key(&key) {
+                let file = ignored_rev_file(git, rev, &line_path);
+                files.insert(key.clone(), file);
+            }
+            let file = match files[&key] {
+                Some(ref file) => file,
+                None => break,
+            };
+
+            let parent_line = line.lineno.checked_sub(1)
+                .and_then(|i| file.mapping.get(&i))
+                .and_then(|i| file.parent_blame.get(*i))
+                .and_then(|parent_line| BlameLine::parse(parent_line));
+            let mut parent_line = match parent_line {
+                Some(parent_line) => parent_line,
+                None => break,
+            };
+
+            if parent_line.path == "%" {
+                parent_line.path = line.path.clone();
+            }
+            parent_line.skipped.push(line.rev.clone());
+            parent_line.skipped.extend(line.skipped.drain(..));
+            *line = parent_line;
+        }
+    }
+}
@@ -0,0 +1,446 @@
+extern crate memmap;
+
+use self::memmap::{Mmap, Protection};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use git2::{Oid, Repository};
+
+// Maps revisions of a tree's repository to the corresponding
+// revisions of its blame repository (and to their ids in other version
+// control systems, such as hg). The
+// map is kept in a file in the blame repository's git directory so
+// that it only needs to be extended with new blame commits rather
+// than rebuilt from the full history each time a server starts. The
+// file is mapped into memory the first time the map is used.
+//
+// File format (integers are little-endian):
+//   magic      b"SFBLAME2"
+//   head       20 bytes; the blame commit the map is current for
+//   count      u64
+//   records    `count` records sorted by original oid, each
+//                original oid (20 bytes), blame oid (20 bytes),
+//                ids offset (u32), ids length (u32)
+//   strings    secondary ids as `<vcs> <id>` lines, referenced by
+//              the records
+pub struct BlameMap {
+    blame_repo_path: Option<PathBuf>,
+    data: RefCell<Option<MapData>>,
+}
+
+enum MapData {
+    Mapped(Mmap),
+    Memory(Vec<u8>),
+}
+
+impl MapData {
+    fn bytes(&self) -> &[u8] {
+        match *self {
+            MapData::Mapped(ref mmap) => unsafe { mmap.as_slice() },
+            MapData::Memory(ref bytes) => bytes,
+        }
+    }
+}
+
+const MAGIC: &'static [u8] = b"SFBLAME2";
+const HEADER_SIZE: usize = 8 + 20 + 8;
+const RECORD_SIZE: usize = 20 + 20 + 4 + 4;
+const MAP_FILENAME: &'static str = "searchfox-blame-map";
+
+struct Entry {
+    orig: Oid,
+    blame: Oid,
+    secondary_ids: Vec<(String, String)>,
+}
+
+fn no_oid() -> Oid {
+    Oid::from_bytes(&[0; 20]).unwrap()
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    (read_u32(&bytes[0 .. 4]) as u64) | (read_u32(&bytes[4 .. 8]) as u64) << 32
+}
+
+fn write_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend(&[n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]);
+}
+
+fn write_u64(out: &mut Vec<u8>, n: u64) {
+    write_u32(out, n as u32);
+    write_u32(out, (n >> 32) as u32);
+}
+
+// Checks that `bytes` holds a complete map and returns its head and
+// number of records.
+fn parse_header(bytes: &[u8]) -> Option<(Oid, usize)> {
+    if bytes.len() < HEADER_SIZE || &bytes[0 .. 8] != MAGIC {
+        return None;
+    }
+    let head = match Oid::from_bytes(&bytes[8 .. 28]) {
+        Ok(head) => head,
+        Err(_) => return None,
+    };
+    let count = read_u64(&bytes[28 .. 36]) as usize;
+    match count.checked_mul(RECORD_SIZE).and_then(|n| n.checked_add(HEADER_SIZE)) {
+        Some(size) if size <= bytes.len() => Some((head, count)),
+        _ => None,
+    }
+}
+
+fn record(bytes: &[u8], i: usize) -> &[u8] {
+    let start = HEADER_SIZE + i * RECORD_SIZE;
+    &bytes[start .. start + RECORD_SIZE]
+}
+
+fn record_ids(bytes: &[u8], count: usize, rec: &[u8]) -> Vec<(String, String)> {
+    let len = read_u32(&rec[44 .. 48]) as usize;
+    let start = HEADER_SIZE + count * RECORD_SIZE + read_u32(&rec[40 .. 44]) as usize;
+    let ids = match start.checked_add(len).and_then(|end| bytes.get(start .. end)).and_then(|s| str::from_utf8(s).ok()) {
+        Some(ids) => ids,
+        None => return vec![],
+    };
+    ids.lines().filter_map(|line| {
+        let mut pieces = line.splitn(2, ' ');
+        match (pieces.next(), pieces.next()) {
+            (Some(vcs), Some(id)) => Some((vcs.to_owned(), id.to_owned())),
+            _ => None,
+        }
+    }).collect()
+}
+
+fn read_entries(bytes: &[u8]) -> Vec<Entry> {
+    let count = match parse_header(bytes) {
+        Some((_, count)) => count,
+        None => return vec![],
+    };
+    (0 .. count).map(|i| {
+        let rec = record(bytes, i);
+        Entry {
+            orig: Oid::from_bytes(&rec[0 .. 20]).unwrap(),
+            blame: Oid::from_bytes(&rec[20 .. 40]).unwrap(),
+            secondary_ids: record_ids(bytes, count, rec),
+        }
+    }).collect()
+}
+
+fn serialize(head: Oid, mut entries: Vec<Entry>) -> Vec<u8> {
+    entries.sort_by(|a, b| a.orig.as_bytes().cmp(b.orig.as_bytes()));
+    entries.dedup_by_key(|e| e.orig);
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + entries.len() * RECORD_SIZE);
+    out.extend(MAGIC);
+    out.extend(head.as_bytes());
+    write_u64(&mut out, entries.len() as u64);
+
+    let mut strings: Vec<u8> = Vec::new();
+    for entry in &entries {
+        out.extend(entry.orig.as_bytes());
+        out.extend(entry.blame.as_bytes());
+        let mut ids = String::new();
+        for &(ref vcs, ref id) in &entry.secondary_ids {
+            ids.push_str(&format!("{} {}\n", vcs, id));
+        }
+        write_u32(&mut out, strings.len() as u32);
+        write_u32(&mut out, ids.len() as u32);
+        strings.extend(ids.as_bytes());
+    }
+    out.extend(strings);
+    out
+}
+
+// The newest blame commit message format that we understand.
+pub const BLAME_FORMAT_VERSION: u32 = 1;
+
+// Parses a blame commit message into the original revision and its
+// ids in other version control systems. See docs/blame.md for the
+// format. Older blame repositories have messages without a
+// `blame-format` line, written as `git <sha> [hg <hg-rev>]`.
+fn parse_blame_message(msg: &str) -> Result<(Oid, Vec<(String, String)>), String> {
+    let mut lines = msg.lines().map(|line| line.trim()).filter(|line| !line.is_empty()).peekable();
+
+    let is_versioned = match lines.peek() {
+        Some(line) => line.starts_with("blame-format "),
+        None => return Err("empty message".to_owned()),
+    };
+
+    let pairs = if is_versioned {
+        let version = lines.next().unwrap()["blame-format ".len() ..].trim();
+        match version.parse::<u32>() {
+            Ok(version) if version <= BLAME_FORMAT_VERSION => {},
+            _ => return Err(format!("unsupported blame-format {}", version)),
+        }
+
+        let mut pairs = vec![];
+        for line in lines {
+            let mut pieces = line.split_whitespace();
+            match (pieces.next(), pieces.next(), pieces.next()) {
+                (Some(vcs), Some(id), None) => pairs.push((vcs, id)),
+                _ => return Err(format!("malformed line `{}`", line)),
+            }
+        }
+        pairs
+    } else {
+        let pieces = msg.split_whitespace().collect::<Vec<_>>();
+        if pieces.len() % 2 != 0 {
+            return Err("not a blame commit".to_owned());
+        }
+        pieces.chunks(2).map(|pair| (pair[0], pair[1])).collect()
+    };
+
+    let mut orig = None;
+    let mut secondary_ids = vec![];
+    for (vcs, id) in pairs {
+        if vcs == "git" {
+            if orig.is_some() {
+                return Err("more than one git revision".to_owned());
+            }
+            orig = Some(try!(Oid::from_str(id).map_err(|_| format!("bad git revision `{}`", id))));
+        } else {
+            secondary_ids.push((vcs.to_owned(), id.to_owned()));
+        }
+    }
+
+    match orig {
+        Some(orig) => Ok((orig, secondary_ids)),
+        None => Err("no git revision".to_owned()),
+    }
+}
+
+fn parse_blame_commit(blame_repo: &Repository, oid: Oid) -> Result<Option<Entry>, git2::Error> {
+    let commit = try!(blame_repo.find_commit(oid));
+
+    let parsed = match commit.message() {
+        Some(msg) => parse_blame_message(msg),
+        None => Err("message is not UTF-8".to_owned()),
+    };
+
+    match parsed {
+        Ok((orig, secondary_ids)) => Ok(Some(Entry { orig: orig, blame: oid, secondary_ids: secondary_ids })),
+        Err(err) => {
+            println!("Skipping blame commit {}: {}", oid, err);
+            Ok(None)
+        },
+    }
+}
+
+// Collects the blame commits reachable from HEAD but not from `known`.
+fn new_entries(blame_repo: &Repository, head: Oid, known: Option<Oid>) -> Result<Vec<Entry>, git2::Error> {
+    let mut walk = try!(blame_repo.revwalk());
+    try!(walk.push(head));
+    if let Some(known) = known {
+        try!(walk.hide(known));
+    }
+
+    let mut entries = Vec::new();
+    for oid in walk {
+        if let Some(entry) = try!(parse_blame_commit(blame_repo, try!(oid))) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+fn write_map(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    // Write to a temporary file first so that other processes never
+    // see a partial map.
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    let tmp_path = path.with_extension(format!("tmp{}", nanos));
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| file.write_all(bytes))
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result.map_err(|err| err.to_string())
+}
+
+fn empty_map() -> MapData {
+    MapData::Memory(serialize(no_oid(), vec![]))
+}
+
+fn load_map(blame_repo_path: &Path) -> Result<MapData, git2::Error> {
+    let blame_repo = try!(Repository::open(blame_repo_path));
+    let map_path = blame_repo.path().join(MAP_FILENAME);
+
+    let head = match blame_repo.refname_to_id("HEAD") {
+        Ok(head) => head,
+        Err(_) => return Ok(empty_map()),
+    };
+
+    let existing = Mmap::open_path(&map_path, Protection::Read).ok();
+    let known = match existing {
+        Some(ref mmap) => parse_header(unsafe { mmap.as_slice() }).map(|(known, _)| known),
+        None => None,
+    };
+
+    if known == Some(head) {
+        return Ok(MapData::Mapped(existing.unwrap()));
+    }
+
+    // Only reuse the existing map if the blame repository was extended
+    // rather than rewritten.
+    let known = match known {
+        Some(known) if blame_repo.merge_base(head, known).ok() == Some(known) => Some(known),
+        _ => None,
+    };
+
+    let mut entries = try!(new_entries(&blame_repo, head, known));
+    if known.is_some() {
+        entries.extend(read_entries(unsafe { existing.as_ref().unwrap().as_slice() }));
+    }
+    let bytes = serialize(head, entries);
+
+    Ok(match write_map(&map_path, &bytes) {
+        Ok(()) => {
+            match Mmap::open_path(&map_path, Protection::Read) {
+                Ok(mmap) => MapData::Mapped(mmap),
+                Err(_) => MapData::Memory(bytes),
+            }
+        },
+        Err(err) => {
+            println!("Unable to write blame map {}: {}", map_path.display(), err);
+            MapData::Memory(bytes)
+        },
+    })
+}
+
+impl BlameMap {
+    pub fn new(blame_repo_path: &str) -> BlameMap {
+        BlameMap {
+            blame_repo_path: Some(PathBuf::from(blame_repo_path)),
+            data: RefCell::new(None),
+        }
+    }
+
+    // A map that never finds anything, for tools that don't need blame.
+    pub fn empty() -> BlameMap {
+        BlameMap {
+            blame_repo_path: None,
+            data: RefCell::new(None),
+        }
+    }
+
+    fn with_bytes<T, F: FnOnce(&[u8]) -> T>(&self, f: F) -> T {
+        let mut data = self.data.borrow_mut();
+        if data.is_none() {
+            // A blame repository that can't be read leaves the map
+            // empty, so pages are shown without blame.
+            *data = Some(match self.blame_repo_path {
+                Some(ref path) => match load_map(path) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        println!("Unable to read blame map from {}: {}", path.display(), err.message());
+                        empty_map()
+                    },
+                },
+                None => empty_map(),
+            });
+        }
+        f(data.as_ref().unwrap().bytes())
+    }
+
+    fn find<T, F: FnOnce(&[u8], usize, &[u8]) -> T>(&self, oid: &Oid, f: F) -> Option<T> {
+        self.with_bytes(|bytes| {
+            let count = match parse_header(bytes) {
+                Some((_, count)) => count,
+                None => return None,
+            };
+
+            let needle = oid.as_bytes();
+            let mut lo = 0;
+            let mut hi = count;
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                let rec = record(bytes, mid);
+                match rec[0 .. 20].cmp(needle) {
+                    Ordering::Less => lo = mid + 1,
+                    Ordering::Greater => hi = mid,
+                    Ordering::Equal => return Some(f(bytes, count, rec)),
+                }
+            }
+            None
+        })
+    }
+
+    // Returns the blame commit for revision `oid` of the tree.
+    pub fn get(&self, oid: &Oid) -> Option<Oid> {
+        self.find(oid, |_, _, rec| Oid::from_bytes(&rec[20 .. 40]).unwrap())
+    }
+
+    // Returns the `(vcs, id)` pairs recorded for revision `oid`, such
+    // as its hg revision.
+    pub fn secondary_ids(&self, oid: &Oid) -> Vec<(String, String)> {
+        self.find(oid, |bytes, count, rec| record_ids(bytes, count, rec)).unwrap_or(vec![])
+    }
+
+    pub fn len(&self) -> usize {
+        self.with_bytes(|bytes| parse_header(bytes).map(|(_, count)| count).unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_blame_message, parse_header, write_u64, BlameMap, MAGIC};
+    use git2::Oid;
+
+    const SHA: &'static str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[test]
+    fn test_parse_legacy() {
+        let (oid, ids) = parse_blame_message(&format!("git {}\nhg abcdef\n", SHA)).unwrap();
+        assert_eq!(oid, Oid::from_str(SHA).unwrap());
+        assert_eq!(ids, vec![("hg".to_owned(), "abcdef".to_owned())]);
+
+        assert!(parse_blame_message(&format!("git {}", SHA)).unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn test_parse_versioned() {
+        let msg = format!("blame-format 1\ngit {}\nhg abcdef\nsvn 1234\n", SHA);
+        let (oid, ids) = parse_blame_message(&msg).unwrap();
+        assert_eq!(oid, Oid::from_str(SHA).unwrap());
+        assert_eq!(ids, vec![("hg".to_owned(), "abcdef".to_owned()),
+                             ("svn".to_owned(), "1234".to_owned())]);
+    }
+
+    #[test]
+    fn test_parse_unrecognized() {
+        assert!(parse_blame_message("Merge branch 'master'\n").is_err());
+        assert!(parse_blame_message("git nothex").is_err());
+        assert!(parse_blame_message("hg abcdef").is_err());
+        assert!(parse_blame_message(&format!("blame-format 2\ngit {}", SHA)).is_err());
+        assert!(parse_blame_message(&format!("blame-format 1\ngit {} extra", SHA)).is_err());
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(Oid::from_str(SHA).unwrap().as_bytes());
+        write_u64(&mut bytes, 0);
+        assert_eq!(parse_header(&bytes).unwrap().1, 0);
+
+        // A count too large for the file, or for a usize, is rejected.
+        bytes.truncate(28);
+        write_u64(&mut bytes, 1);
+        assert!(parse_header(&bytes).is_none());
+        bytes.truncate(28);
+        write_u64(&mut bytes, u64::max_value());
+        assert!(parse_header(&bytes).is_none());
+    }
+
+    #[test]
+    fn test_unreadable_repo() {
+        let map = BlameMap::new("/nonexistent/blame");
+        assert!(map.get(&Oid::from_str(SHA).unwrap()).is_none());
+        assert_eq!(map.len(), 0);
+    }
+}
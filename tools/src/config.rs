@@ -1,33 +1,82 @@
+use std::env;
+use std::error;
+use std::fmt;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
-use std::collections::BTreeMap;
-use std::collections::HashMap;
+use std::path::Path;
+use std::collections::{BTreeMap, HashSet};
 
-use rustc_serialize::json::{self, Json};
+use rustc_serialize::json::{self, Json, DecoderError};
 use rustc_serialize::Decodable;
 
 use git2::{Oid, Repository};
 
+use blame;
+use blame_map::BlameMap;
+use regex::Regex;
+
 #[derive(RustcDecodable, RustcEncodable)]
 pub struct TreeConfigPaths {
     pub index_path: String,
     pub files_path: String,
     pub git_path: Option<String>,
     pub git_blame_path: Option<String>,
+    pub ignore_revs_path: Option<String>,
     pub objdir_path: String,
 }
 
+// Templates for links to services outside of searchfox. `{rev}` and
+// `{path}` are replaced by `expand_url`. Links that have no template
+// point into searchfox itself, or are left out.
+#[derive(RustcDecodable, RustcEncodable, Default)]
+pub struct TreeConfigUrls {
+    pub log: Option<String>, // {rev}, {path}
+    pub raw: Option<String>, // {rev}, {path}
+    pub commit: Option<String>, // {rev}
+    // Keyed by version control system, for ids from the blame repository.
+    pub mirror_rev: Option<BTreeMap<String, String>>, // {rev}
+}
+
+#[derive(RustcDecodable)]
+struct CommitLinkConfig {
+    pattern: String,
+    url: String,
+    prefix: Option<String>,
+}
+
+// Turns matches of `regex` in commit message headers into links to
+// `url`. `{id}` in the URL is replaced by the `id` group (or the whole
+// match), and `{name}` by any other named group. Rules with a `prefix`
+// only apply to headers that start with it.
+pub struct CommitLinkRule {
+    pub regex: Regex,
+    pub url: String,
+    pub prefix: Option<String>,
+}
+
+pub fn expand_url(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut url = template.to_owned();
+    for &(name, value) in vars {
+        url = url.replace(&format!("{{{}}}", name), value);
+    }
+    url
+}
+
 pub struct GitData {
     pub repo: Repository,
     pub blame_repo: Repository,
 
-    pub blame_map: HashMap<Oid, Oid>, // Maps repo OID to blame_repo OID.
-    pub hg_map: HashMap<Oid, String>, // Maps repo OID to Hg rev.
+    pub blame_map: BlameMap, // Maps repo OID to blame_repo OID and other VCS ids.
+
+    // Revisions that blame skips, such as reformatting changes.
+    pub ignore_revs: HashSet<Oid>,
 }
 
 pub struct TreeConfig {
     pub paths: TreeConfigPaths,
+    pub urls: TreeConfigUrls,
+    pub commit_links: Vec<CommitLinkRule>,
     pub git: Option<GitData>,
 }
 
@@ -43,83 +92,311 @@ pub fn get_git(tree_config: &TreeConfig) -> Result<&GitData, &'static str> {
     }
 }
 
-pub fn get_git_path(tree_config: &TreeConfig) -> Result<&str, &'static str> {
-    match &tree_config.paths.git_path {
-        &Some(ref git_path) => Ok(git_path),
-        &None => Err("History data unavailable"),
-    }
+// Describes what is wrong with a config file. `tree` and `field` say
+// where the problem is, if it's in a particular place.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub tree: Option<String>,
+    pub field: Option<String>,
+    pub message: String,
 }
 
-fn index_blame(_repo: &Repository, blame_repo: &Repository) -> (HashMap<Oid, Oid>, HashMap<Oid, String>) {
-    let mut walk = blame_repo.revwalk().unwrap();
-    walk.push_head().unwrap();
+impl ConfigError {
+    fn new(message: String) -> ConfigError {
+        ConfigError { tree: None, field: None, message: message }
+    }
+
+    fn field(field: &str, message: String) -> ConfigError {
+        ConfigError { tree: None, field: Some(field.to_owned()), message: message }
+    }
+
+    fn tree(tree: &str, field: Option<&str>, message: String) -> ConfigError {
+        ConfigError {
+            tree: Some(tree.to_owned()),
+            field: field.map(|f| f.to_owned()),
+            message: message,
+        }
+    }
+}
 
-    let mut blame_map = HashMap::new();
-    let mut hg_map = HashMap::new();
-    for r in walk {
-        let oid = r.unwrap();
-        let commit = blame_repo.find_commit(oid).unwrap();
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.tree, &self.field) {
+            (&Some(ref tree), &Some(ref field)) => write!(f, "Tree {}, {}: {}", tree, field, self.message),
+            (&Some(ref tree), &None) => write!(f, "Tree {}: {}", tree, self.message),
+            (&None, &Some(ref field)) => write!(f, "{}: {}", field, self.message),
+            (&None, &None) => write!(f, "{}", self.message),
+        }
+    }
+}
 
-        let msg = commit.message().unwrap();
-        let pieces = msg.split_whitespace().collect::<Vec<_>>();
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
 
-        let orig_oid = Oid::from_str(pieces[1]).unwrap();
-        blame_map.insert(orig_oid, commit.id());
+// Variables that may appear in config file strings, as in
+// tests/config.json. They are taken from the environment.
+const CONFIG_VARS: [&'static str; 2] = ["WORKING", "MOZSEARCH_PATH"];
 
-        if pieces.len() > 2 {
-            let hg_id = pieces[3].to_owned();
-            hg_map.insert(orig_oid, hg_id);
+fn substitute_vars(s: &str) -> Result<String, String> {
+    let mut result = s.to_owned();
+    for var in CONFIG_VARS.iter() {
+        for pattern in &[format!("${{{}}}", var), format!("${}", var)] {
+            if result.contains(pattern.as_str()) {
+                let value = try!(env::var(var).map_err(|_| format!("${} is used but not set", var)));
+                result = result.replace(pattern.as_str(), &value);
+            }
         }
     }
+    Ok(result)
+}
 
-    (blame_map, hg_map)
+fn substitute_json(json: &Json, tree_name: &str, field: &str) -> Result<Json, ConfigError> {
+    match *json {
+        Json::String(ref s) => {
+            substitute_vars(s).map(Json::String).map_err(|msg| ConfigError::tree(tree_name, Some(field), msg))
+        },
+        Json::Array(ref items) => {
+            let mut result = Vec::new();
+            for item in items {
+                result.push(try!(substitute_json(item, tree_name, field)));
+            }
+            Ok(Json::Array(result))
+        },
+        Json::Object(ref obj) => {
+            let mut result = BTreeMap::new();
+            for (key, value) in obj {
+                let field = if field.is_empty() { key.clone() } else { format!("{}.{}", field, key) };
+                result.insert(key.clone(), try!(substitute_json(value, tree_name, &field)));
+            }
+            Ok(Json::Object(result))
+        },
+        _ => Ok(json.clone()),
+    }
 }
 
-pub fn load(config_path: &str, need_indexes: bool) -> Config {
-    let config_file = File::open(config_path).unwrap();
+fn decode_error(tree_name: &str, field: &str, err: DecoderError) -> ConfigError {
+    let field_name = if field.is_empty() { None } else { Some(field) };
+    match err {
+        DecoderError::MissingFieldError(name) => {
+            let name = if field.is_empty() { name } else { format!("{}.{}", field, name) };
+            ConfigError::tree(tree_name, Some(&name), "Missing field".to_owned())
+        },
+        DecoderError::ExpectedError(expected, found) => {
+            ConfigError::tree(tree_name, field_name,
+                              format!("Expected {}, found {}", expected, found))
+        },
+        err => ConfigError::tree(tree_name, field_name, format!("{:?}", err)),
+    }
+}
+
+fn check_dir(tree_name: &str, field: &str, path: &str) -> Result<(), ConfigError> {
+    if Path::new(path).is_dir() {
+        Ok(())
+    } else {
+        Err(ConfigError::tree(tree_name, Some(field), format!("{} is not a directory", path)))
+    }
+}
+
+fn open_repo(tree_name: &str, field: &str, path: &str) -> Result<Repository, ConfigError> {
+    Repository::open(path).map_err(|err| {
+        ConfigError::tree(tree_name, Some(field), format!("Unable to open git repository {}: {}", path, err.message()))
+    })
+}
+
+fn read_json(config_path: &str) -> Result<Json, ConfigError> {
+    let config_file = try!(File::open(config_path).map_err(|err| {
+        ConfigError::new(format!("Unable to open {}: {}", config_path, err))
+    }));
     let mut reader = BufReader::new(&config_file);
     let mut input = String::new();
-    reader.read_to_string(&mut input).unwrap();
-    let config = Json::from_str(&input).unwrap();
+    try!(reader.read_to_string(&mut input).map_err(|err| {
+        ConfigError::new(format!("Unable to read {}: {}", config_path, err))
+    }));
+    Json::from_str(&input).map_err(|err| {
+        ConfigError::new(format!("Unable to parse {}: {}", config_path, err))
+    })
+}
+
+fn trees_object(config: &Json) -> Result<&json::Object, ConfigError> {
+    match config.find("trees") {
+        Some(&Json::Object(ref trees)) => Ok(trees),
+        Some(_) => Err(ConfigError::field("trees", "Expected an object".to_owned())),
+        None => Err(ConfigError::field("trees", "Missing field".to_owned())),
+    }
+}
+
+pub fn tree_names(config_path: &str) -> Result<Vec<String>, ConfigError> {
+    let config = try!(read_json(config_path));
+    let trees_obj = try!(trees_object(&config));
+    Ok(trees_obj.keys().cloned().collect())
+}
+
+// Every key a tree entry may have. The codesearch ones are only read by
+// the router.
+const TREE_KEYS: [&'static str; 10] = [
+    "index_path", "files_path", "git_path", "git_blame_path", "ignore_revs_path",
+    "objdir_path", "codesearch_path", "codesearch_port", "urls", "commit_links",
+];
+const URL_KEYS: [&'static str; 4] = ["log", "raw", "commit", "mirror_rev"];
+const COMMIT_LINK_KEYS: [&'static str; 3] = ["pattern", "url", "prefix"];
+
+fn unknown_object_keys(tree_name: &str, field: &str, json: &Json, known: &[&str], errors: &mut Vec<ConfigError>) {
+    if let Json::Object(ref obj) = *json {
+        for key in obj.keys() {
+            if !known.contains(&key.as_str()) {
+                let name = if field.is_empty() { key.clone() } else { format!("{}.{}", field, key) };
+                errors.push(ConfigError::tree(tree_name, Some(&name), "Unknown key".to_owned()));
+            }
+        }
+    }
+}
+
+// Finds keys in tree entries that nothing reads, which are usually
+// misspellings of optional ones. `load` ignores them.
+pub fn unknown_keys(config_path: &str, tree_names: Option<&[String]>) -> Result<Vec<ConfigError>, ConfigError> {
+    let config = try!(read_json(config_path));
+    let trees_obj = try!(trees_object(&config));
+
+    let mut errors = Vec::new();
+    for (tree_name, tree_config) in trees_obj {
+        if let Some(tree_names) = tree_names {
+            if !tree_names.contains(tree_name) {
+                continue;
+            }
+        }
+
+        unknown_object_keys(tree_name, "", tree_config, &TREE_KEYS, &mut errors);
+        if let Some(urls) = tree_config.find("urls") {
+            unknown_object_keys(tree_name, "urls", urls, &URL_KEYS, &mut errors);
+        }
+        if let Some(&Json::Array(ref links)) = tree_config.find("commit_links") {
+            for (i, link) in links.iter().enumerate() {
+                unknown_object_keys(tree_name, &format!("commit_links[{}]", i), link, &COMMIT_LINK_KEYS, &mut errors);
+            }
+        }
+    }
+    Ok(errors)
+}
+
+pub fn load(config_path: &str, need_indexes: bool) -> Result<Config, ConfigError> {
+    load_trees(config_path, need_indexes, None)
+}
 
-    let mut obj = config.as_object().unwrap().clone();
+// Like `load`, but skips every tree not listed in `tree_names`.
+pub fn load_trees(config_path: &str, need_indexes: bool, tree_names: Option<&[String]>) -> Result<Config, ConfigError> {
+    let config = try!(read_json(config_path));
 
-    let mozsearch_json = obj.remove("mozsearch_path").unwrap();
-    let mozsearch = mozsearch_json.as_string().unwrap();
+    let mozsearch = match config.find("mozsearch_path") {
+        Some(&Json::String(ref path)) => try!(substitute_vars(path).map_err(|msg| ConfigError::field("mozsearch_path", msg))),
+        Some(_) => return Err(ConfigError::field("mozsearch_path", "Expected a string".to_owned())),
+        None => return Err(ConfigError::field("mozsearch_path", "Missing field".to_owned())),
+    };
+    if !Path::new(&mozsearch).is_dir() {
+        return Err(ConfigError::field("mozsearch_path", format!("{} is not a directory", mozsearch)));
+    }
+
+    let trees_obj = try!(trees_object(&config));
+
+    if let Some(tree_names) = tree_names {
+        for tree_name in tree_names {
+            if !trees_obj.contains_key(tree_name) {
+                return Err(ConfigError::tree(tree_name, None, "Unknown tree".to_owned()));
+            }
+        }
+    }
 
-    let trees_obj = obj.get("trees").unwrap().as_object().unwrap().clone();
-    
     let mut trees = BTreeMap::new();
     for (tree_name, tree_config) in trees_obj {
+        if let Some(tree_names) = tree_names {
+            if !tree_names.contains(tree_name) {
+                continue;
+            }
+        }
+
+        let tree_config = try!(substitute_json(tree_config, tree_name, ""));
+
+        let urls = match tree_config.find("urls") {
+            Some(urls) => {
+                let mut decoder = json::Decoder::new(urls.clone());
+                try!(TreeConfigUrls::decode(&mut decoder).map_err(|err| decode_error(tree_name, "urls", err)))
+            },
+            None => TreeConfigUrls::default(),
+        };
+
+        let commit_links = match tree_config.find("commit_links") {
+            Some(links) => {
+                let mut decoder = json::Decoder::new(links.clone());
+                let links = try!(Vec::<CommitLinkConfig>::decode(&mut decoder).map_err(|err| {
+                    decode_error(tree_name, "commit_links", err)
+                }));
+                let mut rules = Vec::new();
+                for (i, link) in links.into_iter().enumerate() {
+                    let regex = try!(Regex::new(&link.pattern).map_err(|err| {
+                        ConfigError::tree(tree_name, Some(&format!("commit_links[{}].pattern", i)), err.to_string())
+                    }));
+                    rules.push(CommitLinkRule {
+                        regex: regex,
+                        url: link.url,
+                        prefix: link.prefix,
+                    });
+                }
+                rules
+            },
+            None => vec![],
+        };
+
         let mut decoder = json::Decoder::new(tree_config);
-        let paths = TreeConfigPaths::decode(&mut decoder).unwrap();
+        let paths = try!(TreeConfigPaths::decode(&mut decoder).map_err(|err| decode_error(tree_name, "", err)));
+
+        // The objdir isn't checked because only trees with generated
+        // files need it.
+        try!(check_dir(tree_name, "index_path", &paths.index_path));
+        try!(check_dir(tree_name, "files_path", &paths.files_path));
 
         let git = match (&paths.git_path, &paths.git_blame_path) {
             (&Some(ref git_path), &Some(ref git_blame_path)) => {
-                let repo = Repository::open(&git_path).unwrap();
-                let blame_repo = Repository::open(&git_blame_path).unwrap();
+                let repo = try!(open_repo(tree_name, "git_path", git_path));
+                let blame_repo = try!(open_repo(tree_name, "git_blame_path", git_blame_path));
 
-                let (blame_map, hg_map) = if need_indexes {
-                    index_blame(&repo, &blame_repo)
+                let blame_map = if need_indexes {
+                    BlameMap::new(git_blame_path)
                 } else {
-                    (HashMap::new(), HashMap::new())
+                    BlameMap::empty()
+                };
+
+                let ignore_revs = match paths.ignore_revs_path {
+                    Some(ref path) => try!(blame::read_ignore_revs(path).map_err(|err| {
+                        ConfigError::tree(tree_name, Some("ignore_revs_path"), err)
+                    })),
+                    None => HashSet::new(),
                 };
 
                 Some(GitData {
                     repo: repo,
                     blame_repo: blame_repo,
                     blame_map: blame_map,
-                    hg_map: hg_map,
+                    ignore_revs: ignore_revs,
                 })
             },
+            (&Some(_), &None) => {
+                return Err(ConfigError::tree(tree_name, Some("git_blame_path"), "Missing field; required with git_path".to_owned()));
+            },
+            (&None, &Some(_)) => {
+                return Err(ConfigError::tree(tree_name, Some("git_path"), "Missing field; required with git_blame_path".to_owned()));
+            },
             _ => None,
         };
 
-        trees.insert(tree_name, TreeConfig {
+        trees.insert(tree_name.clone(), TreeConfig {
             paths: paths,
+            urls: urls,
+            commit_links: commit_links,
             git: git,
         });
     }
 
-    Config { trees: trees, mozsearch_path: mozsearch.to_owned() }
+    Ok(Config { trees: trees, mozsearch_path: mozsearch })
 }
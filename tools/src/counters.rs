@@ -0,0 +1,22 @@
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+// Process-wide counts of git object lookups, reported by the web
+// server's metrics endpoint.
+static GIT_LOOKUPS: AtomicUsize = ATOMIC_USIZE_INIT;
+static BLAME_LOOKUPS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+pub fn count_git_lookup() {
+    GIT_LOOKUPS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_blame_lookup() {
+    BLAME_LOOKUPS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn git_lookups() -> usize {
+    GIT_LOOKUPS.load(Ordering::Relaxed)
+}
+
+pub fn blame_lookups() -> usize {
+    BLAME_LOOKUPS.load(Ordering::Relaxed)
+}
@@ -1,16 +1,20 @@
 use std::io::Write;
+use std::cmp;
 use std::collections::HashMap;
 use std::path::Path;
-use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use file_format::analysis;
 use blame;
+use counters;
 use tokenize;
+use word_diff;
 use languages;
 use languages::FormatAs;
 
 use file_format::analysis::{WithLocation, AnalysisSource, Jump};
-use output::{self, F, Options, PanelItem, PanelSection};
+use output::{self, F, Options, PanelItem, PanelSection, entity_replace};
 
 use rustc_serialize::json::{self, Json};
 use git2;
@@ -45,10 +49,6 @@ pub fn format_code(jumps: &HashMap<String, Jump>, format: FormatAs,
 
     let mut cur_datum = 0;
 
-    fn entity_replace(s: String) -> String {
-        s.replace("&", "&amp;").replace("<", "&lt;")
-    }
-
     let mut generated_json = json::Array::new();
 
     let mut last_pos = 0;
@@ -174,15 +174,15 @@ pub fn format_code(jumps: &HashMap<String, Jump>, format: FormatAs,
 
         match token.kind {
             tokenize::TokenKind::Punctuation | tokenize::TokenKind::PlainText => {
-                output.push_str(&entity_replace(input[last .. token.start].to_string()));
-                output.push_str(&entity_replace(input[token.start .. token.end].to_string()));
+                output.push_str(&entity_replace(&input[last .. token.start]));
+                output.push_str(&entity_replace(&input[token.start .. token.end]));
                 last = token.end;
             },
             _ => {
                 if style != "" || data != "" {
-                    output.push_str(&entity_replace(input[last .. token.start].to_string()));
+                    output.push_str(&entity_replace(&input[last .. token.start]));
                     output.push_str(&format!("<span {}{}>", style, data));
-                    output.push_str(&entity_replace(input[token.start .. token.end].to_string()));
+                    output.push_str(&entity_replace(&input[token.start .. token.end]));
                     output.push_str("</span>");
                     last = token.end;
                 }
@@ -190,7 +190,7 @@ pub fn format_code(jumps: &HashMap<String, Jump>, format: FormatAs,
         }
     }
 
-    output.push_str(&entity_replace(input[last ..].to_string()));
+    output.push_str(&entity_replace(&input[last ..]));
 
     if output.len() > 0 {
         output_lines.push(fixup(output));
@@ -220,6 +220,85 @@ fn read_blob_entry(repo: &git2::Repository, entry: &git2::TreeEntry) -> String {
     decode_bytes(content)
 }
 
+// Lines are colored by age relative to the tree's head when the blame
+// heatmap is on. Each bucket holds changes younger than its limit; the
+// last one holds everything older.
+const BLAME_AGE_BUCKETS: [(i64, &'static str); 6] = [
+    (7 * 24 * 60 * 60, "1 week"),
+    (30 * 24 * 60 * 60, "1 month"),
+    (91 * 24 * 60 * 60, "3 months"),
+    (365 * 24 * 60 * 60, "1 year"),
+    (2 * 365 * 24 * 60 * 60, "2 years"),
+    (5 * 365 * 24 * 60 * 60, "5 years"),
+];
+
+fn blame_age_legend() -> F {
+    let mut items = BLAME_AGE_BUCKETS.iter().enumerate().map(|(i, &(_, label))| {
+        F::T(format!("<span class=\"age{}\">&lt; {}</span>", i, label))
+    }).collect::<Vec<_>>();
+    items.push(F::T(format!("<span class=\"age{}\">older</span>", BLAME_AGE_BUCKETS.len())));
+
+    F::Seq(vec![
+        F::S("<div id=\"blame-age-legend\" class=\"blame-age-legend\">"),
+        F::Indent(items),
+        F::S("</div>"),
+    ])
+}
+
+// Returns the age bucket of each line's revision.
+fn blame_age_classes(git: &config::GitData, lines: &[blame::BlameLine]) -> Vec<Option<usize>> {
+    counters::count_git_lookup();
+    let head_time = git.repo.refname_to_id("HEAD").and_then(|oid| git.repo.find_commit(oid))
+        .map(|commit| commit.time().seconds());
+
+    let mut rev_ages: HashMap<&str, Option<usize>> = HashMap::new();
+    lines.iter().map(|line| {
+        let head_time = match head_time {
+            Ok(head_time) => head_time,
+            Err(_) => return None,
+        };
+        *rev_ages.entry(&line.rev).or_insert_with(|| {
+            counters::count_git_lookup();
+            let time = match git2::Oid::from_str(&line.rev).and_then(|oid| git.repo.find_commit(oid)) {
+                Ok(commit) => commit.time().seconds(),
+                Err(_) => return None,
+            };
+            let age = head_time - time;
+            Some(BLAME_AGE_BUCKETS.iter().position(|&(limit, _)| age < limit).unwrap_or(BLAME_AGE_BUCKETS.len()))
+        })
+    }).collect()
+}
+
+// Returns the attributes of the blame strip for a line. `color`
+// alternates between revisions, and `age` is the line's age bucket.
+fn blame_strip_attrs(blame_line: &blame::BlameLine, color: bool, age: Option<usize>) -> String {
+    let mut class = format!("c{}", if color { 1 } else { 2 });
+    if let Some(age) = age {
+        class.push_str(&format!(" age{}", age));
+    }
+    if blame_line.skipped.is_empty() {
+        format!(" class=\"blame-strip {}\" data-blame=\"{}#{}#{}\"",
+                class, blame_line.rev, blame_line.path, blame_line.lineno)
+    } else {
+        format!(" class=\"blame-strip {} blame-ignored\" data-blame=\"{}#{}#{}#{}\"",
+                class, blame_line.rev, blame_line.path, blame_line.lineno, blame_line.skipped.join(","))
+    }
+}
+
+pub fn log_url(tree_name: &str, urls: &config::TreeConfigUrls, rev: &str, path: &str) -> String {
+    match urls.log {
+        Some(ref template) => config::expand_url(template, &[("rev", rev), ("path", path)]),
+        None => format!("/{}/log/{}/{}", tree_name, rev, path),
+    }
+}
+
+pub fn raw_url(tree_name: &str, urls: &config::TreeConfigUrls, rev: &str, path: &str) -> String {
+    match urls.raw {
+        Some(ref template) => config::expand_url(template, &[("rev", rev), ("path", path)]),
+        None => format!("/{}/raw-rev/{}/{}", tree_name, rev, path),
+    }
+}
+
 pub fn format_file_data(cfg: &config::Config,
                         tree_name: &str,
                         panel: &[PanelSection],
@@ -229,6 +308,7 @@ pub fn format_file_data(cfg: &config::Config,
                         data: String,
                         jumps: &HashMap<String, Jump>,
                         analysis: &[WithLocation<Vec<AnalysisSource>>],
+                        color_by_age: bool,
                         writer: &mut Write) -> Result<(), &'static str>  {
     let tree_config = try!(cfg.trees.get(tree_name).ok_or("Invalid tree"));
 
@@ -243,15 +323,20 @@ pub fn format_file_data(cfg: &config::Config,
 
     let (output_lines, analysis_json) = format_code(jumps, format, path, &data, &analysis);
 
-    let mut _blame = String::new();
     let blame_lines = match (&tree_config.git, blame_commit) {
         (&Some(ref git_data), Some(blame_commit)) => {
             let blame_tree = try!(blame_commit.tree().map_err(|_| "Bad revision"));
 
             match blame_tree.get_path(Path::new(path)) {
                 Ok(blame_entry) => {
-                    _blame = read_blob_entry(&git_data.blame_repo, &blame_entry);
-                    Some(_blame.lines().collect::<Vec<_>>())
+                    counters::count_blame_lookup();
+                    let blame = read_blob_entry(&git_data.blame_repo, &blame_entry);
+                    let mut lines = Vec::new();
+                    for line in blame.lines() {
+                        lines.push(try!(blame::BlameLine::parse(line).ok_or("Bad blame data")));
+                    }
+                    blame::skip_ignored_revs(git_data, path, &mut lines);
+                    Some(lines)
                 },
                 Err(_) => None,
             }
@@ -262,7 +347,7 @@ pub fn format_file_data(cfg: &config::Config,
     let revision_owned = match commit {
         Some(commit) => {
             let rev = commit.id().to_string();
-            let (header, _) = try!(blame::commit_header(commit));
+            let (header, _) = try!(blame::commit_header(commit, &tree_config.commit_links));
             Some((rev, header))
         },
         None => None,
@@ -285,7 +370,32 @@ pub fn format_file_data(cfg: &config::Config,
 
     try!(output::generate_breadcrumbs(&opt, writer, path));
 
-    try!(output::generate_panel(writer, panel));
+    // Static pages can't depend on the query, so they link to the
+    // current revision's page to color by age.
+    let age_link = match commit {
+        Some(_) => "?blame=age".to_owned(),
+        None => format!("/{}/rev/HEAD/{}?blame=age", tree_name, path),
+    };
+    let mut panel = panel.to_vec();
+    if blame_lines.is_some() {
+        panel.push(PanelSection {
+            name: "Blame".to_owned(),
+            items: vec![PanelItem {
+                title: "Color by age".to_owned(),
+                link: age_link,
+                update_link_lineno: false,
+            }],
+        });
+    }
+    try!(output::generate_panel(writer, &panel));
+
+    let blame_ages = match (&tree_config.git, &blame_lines) {
+        (&Some(ref git_data), &Some(ref lines)) if color_by_age => {
+            try!(output::generate_formatted(writer, &blame_age_legend(), 0));
+            Some(blame_age_classes(git_data, lines))
+        },
+        _ => None,
+    };
 
     let f = F::Seq(vec![
         F::S("<table id=\"file\" class=\"file\">"),
@@ -315,19 +425,13 @@ pub fn format_file_data(cfg: &config::Config,
         let lineno = i + 1;
 
         let blame_data = if let Some(ref lines) = blame_lines {
-            let blame_line = lines[i as usize];
-            let pieces = blame_line.splitn(4, ':').collect::<Vec<_>>();
-            let rev = pieces[0];
-            let filespec = pieces[1];
-            let blame_lineno = pieces[2];
+            let blame_line = &lines[i as usize];
+            let rev = blame_line.rev.as_str();
 
             let color = if last_rev == Some(rev) { last_color } else { !last_color };
             last_rev = Some(rev);
             last_color = color;
-            let class = if color { 1 } else { 2 };
-            let data = format!(" class=\"blame-strip c{}\" data-blame=\"{}#{}#{}\"",
-                               class, rev, filespec, blame_lineno);
-            data
+            blame_strip_attrs(blame_line, color, blame_ages.as_ref().and_then(|ages| ages[i]))
         } else {
             "".to_owned()
         };
@@ -381,22 +485,39 @@ pub fn format_file_data(cfg: &config::Config,
     Ok(())
 }
 
+// Writes the page for `path` at `rev`, which is a file or directory.
+// Returns false if a directory page is missing some of its entries'
+// last changes because finding them took too long, so that the page
+// isn't treated as final.
 pub fn format_path(cfg: &config::Config,
                    tree_name: &str,
                    rev: &str,
                    path: &str,
-                   writer: &mut Write) -> Result<(), &'static str> {
+                   color_by_age: bool,
+                   writer: &mut Write) -> Result<bool, &'static str> {
     // Get the file data.
     let tree_config = try!(cfg.trees.get(tree_name).ok_or("Invalid tree"));
     let git = try!(config::get_git(tree_config));
+    counters::count_git_lookup();
     let commit_obj = try!(git.repo.revparse_single(rev).map_err(|_| "Bad revision"));
     let commit = try!(commit_obj.as_commit().ok_or("Bad revision"));
     let commit_tree = try!(commit.tree().map_err(|_| "Bad revision"));
+    let path = path.trim_right_matches('/');
+    if path.is_empty() {
+        return format_dir(tree_name, &tree_config.commit_links, &git.repo, &commit, &commit_tree, path, writer);
+    }
     let entry = try!(commit_tree.get_path(Path::new(path)).map_err(|_| "File not found"));
 
+    if entry.kind() == Some(git2::ObjectType::Tree) {
+        counters::count_git_lookup();
+        let tree = try!(git.repo.find_tree(entry.id()).map_err(|_| "Bad revision"));
+        return format_dir(tree_name, &tree_config.commit_links, &git.repo, &commit, &tree, path, writer);
+    }
+
     // Get blame.
     let blame_oid = try!(git.blame_map.get(&commit.id()).ok_or("Unable to find blame for revision"));
-    let blame_commit = try!(git.blame_repo.find_commit(*blame_oid).map_err(|_| "Blame is not a blob"));
+    counters::count_blame_lookup();
+    let blame_commit = try!(git.blame_repo.find_commit(blame_oid).map_err(|_| "Blame is not a blob"));
 
     match entry.kind() {
         Some(git2::ObjectType::Blob) => {},
@@ -407,6 +528,7 @@ pub fn format_path(cfg: &config::Config,
         return Err("Path is to a symlink");
     }
 
+    counters::count_git_lookup();
     let data = read_blob_entry(&git.repo, &entry);
 
     let jumps : HashMap<String, analysis::Jump> = HashMap::new();
@@ -420,7 +542,11 @@ pub fn format_path(cfg: &config::Config,
             update_link_lineno: true,
         }, PanelItem {
             title: "Log".to_owned(),
-            link: format!("https://hg.mozilla.org/mozilla-central/log/tip/{}", path),
+            link: log_url(tree_name, &tree_config.urls, rev, path),
+            update_link_lineno: false,
+        }, PanelItem {
+            title: "Raw".to_owned(),
+            link: raw_url(tree_name, &tree_config.urls, rev, path),
             update_link_lineno: false,
         }, PanelItem {
             title: "Blame".to_owned(),
@@ -438,328 +564,1508 @@ pub fn format_path(cfg: &config::Config,
                           data,
                           &jumps,
                           &analysis,
+                          color_by_age,
                           writer));
 
-    Ok(())
+    Ok(true)
 }
 
-fn split_lines(s: &str) -> Vec<&str> {
-    let mut split = s.split('\n').collect::<Vec<_>>();
-    if split[split.len() - 1].len() == 0 {
-        split.pop();
-    }
-    split
+// Stop looking for the last change to a directory entry after this
+// many commits, or this long. Entries that haven't changed in that
+// long are shown without one.
+const MAX_LAST_CHANGE_COMMITS: usize = 5000;
+const MAX_LAST_CHANGE_MILLIS: u64 = 250;
+
+// Number of directories whose last changes are remembered.
+const LAST_CHANGE_CACHE_SIZE: usize = 1000;
+
+lazy_static! {
+    // Last changes by commit and directory, for walks that weren't cut
+    // short. A commit's history never changes, so entries stay valid
+    // across reloads.
+    static ref LAST_CHANGE_CACHE: Mutex<HashMap<(git2::Oid, String), HashMap<String, git2::Oid>>> =
+        Mutex::new(HashMap::new());
 }
 
-pub fn format_diff(cfg: &config::Config,
-                   tree_name: &str,
-                   rev: &str,
-                   path: &str,
-                   writer: &mut Write) -> Result<(), &'static str> {
-    let tree_config = try!(cfg.trees.get(tree_name).ok_or("Invalid tree"));
-
-    let git_path = try!(config::get_git_path(tree_config));
-    let output = try!(Command::new("/usr/bin/git")
-                      .arg("diff-tree")
-                      .arg("-p")
-                      .arg("--cc")
-                      .arg("--patience")
-                      .arg("--full-index")
-                      .arg("--no-prefix")
-                      .arg("-U100000")
-                      .arg(rev)
-                      .arg("--")
-                      .arg(path)
-                      .current_dir(&git_path)
-                      .output().map_err(|_| "Diff failed 1"));
-    if !output.status.success() {
-        println!("ERR\n{}", decode_bytes(output.stderr));
-        return Err("Diff failed 2");
-    }
-    let difftxt = decode_bytes(output.stdout);
-
-    if difftxt.len() == 0 {
-        return format_path(cfg, tree_name, rev, path, writer);
+fn dir_tree_id(commit: &git2::Commit, path: &str) -> Option<git2::Oid> {
+    let tree = match commit.tree() {
+        Ok(tree) => tree,
+        Err(_) => return None,
+    };
+    if path.is_empty() {
+        return Some(tree.id());
+    }
+    match tree.get_path(Path::new(path)) {
+        Ok(entry) => Some(entry.id()),
+        Err(_) => None,
     }
+}
 
-    let git = try!(config::get_git(tree_config));
-    let commit_obj = try!(git.repo.revparse_single(rev).map_err(|_| "Bad revision"));
-    let commit = try!(commit_obj.as_commit().ok_or("Bad revision"));
+// Finds the commit that last changed each entry of the directory
+// `path`, walking back from `commit`. Also returns whether the walk was
+// complete rather than stopped by the limits above.
+fn find_last_changes<'a>(repo: &'a git2::Repository,
+                         commit: &git2::Commit,
+                         path: &str,
+                         dir: &git2::Tree) -> (HashMap<String, git2::Commit<'a>>, bool) {
+    let key = (commit.id(), path.to_owned());
+    let cached = match LAST_CHANGE_CACHE.lock() {
+        Ok(cache) => cache.get(&key).cloned(),
+        Err(poisoned) => poisoned.into_inner().get(&key).cloned(),
+    };
+    let (last_changes, complete) = match cached {
+        Some(last_changes) => (last_changes, true),
+        None => {
+            let (last_changes, complete) = walk_last_changes(repo, commit, path, dir);
+            if complete {
+                let mut cache = match LAST_CHANGE_CACHE.lock() {
+                    Ok(cache) => cache,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                if cache.len() >= LAST_CHANGE_CACHE_SIZE {
+                    cache.clear();
+                }
+                cache.insert(key, last_changes.clone());
+            }
+            (last_changes, complete)
+        },
+    };
 
-    let mut blames = Vec::new();
+    let last_changes = last_changes.into_iter().filter_map(|(name, oid)| {
+        counters::count_git_lookup();
+        repo.find_commit(oid).ok().map(|c| (name, c))
+    }).collect();
+    (last_changes, complete)
+}
 
-    for parent_oid in commit.parent_ids() {
-        let blame_oid = try!(git.blame_map.get(&parent_oid).ok_or("Unable to find blame"));
-        let blame_commit = try!(git.blame_repo.find_commit(*blame_oid).map_err(|_| "Blame is not a blob"));
-        let blame_tree = try!(blame_commit.tree().map_err(|_| "Bad revision"));
-        match blame_tree.get_path(Path::new(path)) {
-            Ok(blame_entry) => {
-                let blame = read_blob_entry(&git.blame_repo, &blame_entry);
-                let blame_lines = blame.lines().map(|s| s.to_owned()).collect::<Vec<_>>();
-                blames.push(Some(blame_lines));
-            },
-            Err(_) => {
-                blames.push(None);
-            }
+fn walk_last_changes(repo: &git2::Repository,
+                     commit: &git2::Commit,
+                     path: &str,
+                     dir: &git2::Tree) -> (HashMap<String, git2::Oid>, bool) {
+    let start = Instant::now();
+    let max_time = Duration::from_millis(MAX_LAST_CHANGE_MILLIS);
+
+    let mut pending = HashMap::new();
+    for entry in dir.iter() {
+        if let Some(name) = entry.name() {
+            pending.insert(name.to_owned(), entry.id());
         }
     }
 
-    let mut new_lineno = 1;
-    let mut old_lineno = commit.parent_ids().map(|_| 1).collect::<Vec<_>>();
+    let mut result = HashMap::new();
+    let mut walk = match repo.revwalk() {
+        Ok(walk) => walk,
+        Err(_) => return (result, false),
+    };
+    if walk.push(commit.id()).is_err() {
+        return (result, false);
+    }
 
-    let mut lines = split_lines(&difftxt);
-    for i in 0..lines.len() {
-        if lines[i].starts_with('@') && i + 1 < lines.len() {
-            lines = lines.split_off(i + 1);
+    let mut walked = 0;
+    for oid in walk {
+        if pending.is_empty() {
             break;
         }
-    }
+        walked += 1;
+        if walked > MAX_LAST_CHANGE_COMMITS || start.elapsed() > max_time {
+            return (result, false);
+        }
 
-    let mut new_lines = String::new();
+        counters::count_git_lookup();
+        let c = match oid.and_then(|oid| repo.find_commit(oid)) {
+            Ok(c) => c,
+            Err(_) => return (result, false),
+        };
+        let tree_id = match dir_tree_id(&c, path) {
+            Some(id) => id,
+            None => continue,
+        };
+        let tree = match repo.find_tree(tree_id) {
+            Ok(tree) => tree,
+            Err(_) => continue,
+        };
+        let parent_trees = c.parents().map(|p| {
+            dir_tree_id(&p, path).and_then(|id| repo.find_tree(id).ok())
+        }).collect::<Vec<_>>();
 
-    let mut output = Vec::new();
-    for line in lines {
-        if line.len() == 0 || line.starts_with('\\') {
+        // Nothing in the directory changed relative to this parent.
+        if parent_trees.iter().any(|t| t.as_ref().map(|t| t.id()) == Some(tree_id)) {
             continue;
         }
 
-        let num_parents = commit.parents().count();
-        let (origin, content) = line.split_at(num_parents);
-        let origin = origin.chars().collect::<Vec<_>>();
-        let mut cur_blame = None;
-        for i in 0..num_parents {
-            let has_minus = origin.contains(&'-');
-            if (has_minus && origin[i] == '-') ||
-                (!has_minus && origin[i] != '+')
-            {
-                cur_blame = match blames[i] {
-                    Some(ref lines) => Some(&lines[old_lineno[i] - 1]),
-                    None => panic!("expected blame for '-' line, none found"),
-                };
-                old_lineno[i] += 1;
-            }
-        }
-
-        let mut lno = -1;
-        if !origin.contains(&'-') {
-            new_lines.push_str(content);
-            new_lines.push('\n');
+        let changed = pending.iter().filter(|&(name, id)| {
+            let cur = tree.get_name(name).map(|e| e.id());
+            cur == Some(*id) && parent_trees.iter().all(|t| {
+                t.as_ref().and_then(|t| t.get_name(name)).map(|e| e.id()) != cur
+            })
+        }).map(|(name, _)| name.clone()).collect::<Vec<_>>();
 
-            lno = new_lineno;
-            new_lineno += 1;
+        for name in changed {
+            pending.remove(&name);
+            result.insert(name, c.id());
         }
-
-        output.push((lno, cur_blame, origin, content));
     }
 
-    let format = languages::select_formatting(path);
-    match format {
-        FormatAs::Binary => {
-            return Err("Cannot diff binary file");
-        },
-        _ => {},
-    };
-    let jumps : HashMap<String, analysis::Jump> = HashMap::new();
-    let analysis = Vec::new();
-    let (formatted_lines, _) = format_code(&jumps, format, path, &new_lines, &analysis);
+    (result, true)
+}
 
-    let (header, _) = try!(blame::commit_header(&commit));
+fn format_dir(tree_name: &str,
+              commit_links: &[config::CommitLinkRule],
+              repo: &git2::Repository,
+              commit: &git2::Commit,
+              dir: &git2::Tree,
+              path: &str,
+              writer: &mut Write) -> Result<bool, &'static str> {
+    let (last_changes, complete) = find_last_changes(repo, commit, path, dir);
+
+    let mut entries = dir.iter().filter_map(|entry| {
+        entry.name().map(|name| (name.to_owned(), entry.clone()))
+    }).collect::<Vec<_>>();
 
-    let filename = Path::new(path).file_name().unwrap().to_str().unwrap();
-    let title = format!("{} - mozsearch", filename);
+    // Same order as output-dir.js: generated files, then dotfiles,
+    // then everything else.
+    entries.sort_by_key(|&(ref name, _)| {
+        let prio = if name == "__GENERATED__" {
+            0
+        } else if name.starts_with('.') {
+            1
+        } else {
+            2
+        };
+        (prio, name.to_lowercase())
+    });
+
+    let rev = commit.id().to_string();
+    let (header, _) = try!(blame::commit_header(commit, commit_links));
+
+    let dirname = match path.rfind('/') {
+        Some(i) => &path[i + 1 ..],
+        None => if path.is_empty() { "/" } else { path },
+    };
+    let title = format!("{} - mozsearch", dirname);
     let opt = Options {
         title: &title,
         tree_name: tree_name,
         include_date: true,
-        revision: Some((rev, &header)),
+        revision: Some((&rev, &header)),
     };
 
-    try!(output::generate_header(&opt, writer));
-
-    let sections = vec![PanelSection {
+    let panel = vec![PanelSection {
         name: "Revision control".to_owned(),
         items: vec![PanelItem {
-            title: "Show changeset".to_owned(),
-            link: format!("/{}/commit/{}", tree_name, rev),
-            update_link_lineno: false,
-        }, PanelItem {
-            title: "Show file without diff".to_owned(),
-            link: format!("/{}/rev/{}/{}", tree_name, rev, path),
-            update_link_lineno: true,
-        }, PanelItem {
             title: "Go to latest version".to_owned(),
             link: format!("/{}/source/{}", tree_name, path),
-            update_link_lineno: true,
-        }, PanelItem {
-            title: "Log".to_owned(),
-            link: format!("https://hg.mozilla.org/mozilla-central/log/tip/{}", path),
             update_link_lineno: false,
         }],
     }];
-    try!(output::generate_panel(writer, &sections));
 
-    let f = F::Seq(vec![
-        F::S("<table id=\"file\" class=\"file\">"),
-        F::Indent(vec![
-            F::S("<thead class=\"visually-hidden\">"),
-            F::Indent(vec![
-                F::S("<th scope=\"col\">Line</th>"),
-                F::S("<th scope=\"col\">Code</th>"),
-            ]),
-            F::S("</thead>"),
+    try!(output::generate_header(&opt, writer));
 
-            F::S("<tbody>"),
-            F::Indent(vec![
-                F::S("<tr>"),
-                F::Indent(vec![
-                    F::S("<td id=\"line-numbers\">"),
-                ]),
-            ]),
-        ]),
-    ]);
+    try!(output::generate_breadcrumbs(&opt, writer, path));
 
-    output::generate_formatted(writer, &f, 0).unwrap();
+    try!(output::generate_panel(writer, &panel));
 
-    let mut last_rev = None;
-    let mut last_color = false;
-    for &(lineno, blame, ref _origin, _content) in &output {
-        let blame_data = match blame {
-            Some(blame) => {
-                let pieces = blame.splitn(4, ':').collect::<Vec<_>>();
-                let rev = pieces[0];
-                let filespec = pieces[1];
-                let blame_lineno = pieces[2];
-
-                let color = if last_rev == Some(rev) { last_color } else { !last_color };
-                last_rev = Some(rev);
-                last_color = color;
-                let class = if color { 1 } else { 2 };
-                format!(" class=\"blame-strip c{}\" data-blame=\"{}#{}#{}\"",
-                        class, rev, filespec, blame_lineno)
+    let mut rows = vec![];
+    for (name, entry) in entries {
+        let entry_path = if path.is_empty() { name.clone() } else { format!("{}/{}", path, name) };
+        let url = output::file_url(&opt, &entry_path);
+
+        counters::count_git_lookup();
+        let (icon, size) = match entry.kind() {
+            Some(git2::ObjectType::Tree) => {
+                let size = repo.find_tree(entry.id()).map(|t| t.len().to_string());
+                ("folder".to_owned(), size.unwrap_or_default())
             },
-            None => "".to_owned(),
+            Some(git2::ObjectType::Blob) => {
+                let size = repo.find_blob(entry.id()).map(|b| b.content().len().to_string());
+                (output::choose_icon(&name), size.unwrap_or_default())
+            },
+            _ => ("".to_owned(), "".to_owned()),
         };
 
-        let line_str = if lineno > 0 {
-            format!("<span id=\"l{}\" class=\"line-number\">{}", lineno, lineno)
-        } else {
-            "<span class=\"line-number\">&nbsp;".to_owned()
+        let last_change = match last_changes.get(&name) {
+            Some(c) => {
+                let (header, _) = try!(blame::commit_header(c, commit_links));
+                let date = NaiveDateTime::from_timestamp(c.time().seconds(), 0);
+                let id = c.id().to_string();
+                format!("<a href=\"/{}/commit/{}\">{}</a> {} {}",
+                        tree_name, id, &id[..8], date.format("%Y-%m-%d"), header)
+            },
+            None => "".to_owned(),
         };
-        let f = F::Seq(vec![
-            F::T(line_str),
-            F::T(format!("<div{}></div>", blame_data)),
-            F::S("</span>")
-        ]);
-
-        output::generate_formatted(writer, &f, 0).unwrap();
-    }
 
-    let f = F::Seq(vec![
-        F::Indent(vec![
+        rows.push(F::Seq(vec![
+            F::S("<tr>"),
             F::Indent(vec![
-                F::Indent(vec![
-                    F::S("</td>"),
-                    F::S("<td class=\"code\">"),
-                ]),
+                F::T(format!("<td><a href=\"{}\" class=\"icon {}\">{}</a></td>",
+                             entity_replace(&url), icon, entity_replace(&name))),
+                F::T(format!("<td><a href=\"{}\">{}</a></td>", entity_replace(&url), size)),
+                F::T(format!("<td class=\"last-change\">{}</td>", last_change)),
             ]),
-        ]),
-    ]);
-    output::generate_formatted(writer, &f, 0).unwrap();
-
-    fn entity_replace(s: String) -> String {
-        s.replace("&", "&amp;").replace("<", "&lt;")
-    }
-
-    write!(writer, "<pre>").unwrap();
-    for &(lineno, _blame, ref origin, content) in &output {
-        let content = entity_replace(content.to_owned());
-        let content = if lineno > 0 && (lineno as usize) < formatted_lines.len() + 1 {
-            &formatted_lines[(lineno as usize) - 1]
-        } else {
-            &content
-        };
-
-        let origin = origin.iter().cloned().collect::<String>();
-
-        let class = if origin.contains('-') {
-            " class=\"minus-line\""
-        } else if origin.contains('+') {
-            " class=\"plus-line\""
-        } else {
-            ""
-        };
-
-        write!(writer, "<code id=\"line-{}\" aria-labelledby=\"{}\"{}>", lineno, lineno, class).unwrap();
-        write!(writer, "{} {}", origin, content).unwrap();
-        write!(writer, "\n</code>").unwrap();
+            F::S("</tr>"),
+        ]));
     }
-    write!(writer, "</pre>").unwrap();
 
     let f = F::Seq(vec![
+        F::S("<table class=\"folder-content\">"),
         F::Indent(vec![
+            F::S("<thead>"),
             F::Indent(vec![
+                F::S("<tr>"),
                 F::Indent(vec![
-                    F::S("</td>"),
+                    F::S("<th scope=\"col\">Name</th>"),
+                    F::S("<th scope=\"col\">Size</th>"),
+                    F::S("<th scope=\"col\">Last change</th>"),
                 ]),
                 F::S("</tr>"),
             ]),
+            F::S("</thead>"),
+            F::S("<tbody>"),
+            F::Indent(rows),
             F::S("</tbody>"),
         ]),
         F::S("</table>"),
     ]);
-    output::generate_formatted(writer, &f, 0).unwrap();
+
+    try!(output::generate_formatted(writer, &f, 0));
 
     output::generate_footer(&opt, tree_name, path, writer).unwrap();
 
-    Ok(())
+    Ok(complete)
 }
 
-fn generate_commit_info(tree_name: &str,
-                        tree_config: &config::TreeConfig,
-                        writer: &mut Write,
-                        commit: &git2::Commit)  -> Result<(), &'static str> {
-    let (header, remainder) = try!(blame::commit_header(&commit));
+// Returns the contents of `path` as of `rev`, without decoding them.
+pub fn read_path_blob(cfg: &config::Config,
+                      tree_name: &str,
+                      rev: &str,
+                      path: &str) -> Result<Vec<u8>, &'static str> {
+    let tree_config = try!(cfg.trees.get(tree_name).ok_or("Invalid tree"));
+    let git = try!(config::get_git(tree_config));
+    counters::count_git_lookup();
+    let commit_obj = try!(git.repo.revparse_single(rev).map_err(|_| "Bad revision"));
+    let commit = try!(commit_obj.as_commit().ok_or("Bad revision"));
+    let commit_tree = try!(commit.tree().map_err(|_| "Bad revision"));
+    let entry = try!(commit_tree.get_path(Path::new(path)).map_err(|_| "File not found"));
 
-    fn format_rev(tree_name: &str, oid: git2::Oid) -> String {
-        format!("<a href=\"/{}/commit/{}\">{}</a>", tree_name, oid, oid)
+    match entry.kind() {
+        Some(git2::ObjectType::Blob) => {},
+        _ => return Err("Invalid path; expected file"),
     }
 
-    fn format_sig(sig: git2::Signature) -> String {
-        format!("{} &lt;{}>", sig.name().unwrap(), sig.email().unwrap())
-    }
+    counters::count_git_lookup();
+    let blob_obj = try!(entry.to_object(&git.repo).map_err(|_| "File not found"));
+    let blob = try!(blob_obj.as_blob().ok_or("Invalid path; expected file"));
+    Ok(blob.content().to_vec())
+}
 
-    let parents = commit.parent_ids().map(|p| {
-        F::T(format!("<tr><td>parent</td><td>{}</td></tr>", format_rev(tree_name, p)))
-    }).collect::<Vec<_>>();
+// Returns whether `path` is a file in the tree's repository at HEAD, or
+// None if the tree has no repository.
+pub fn is_tracked_file(tree_config: &config::TreeConfig, path: &str) -> Option<bool> {
+    let git = match tree_config.git {
+        Some(ref git) => git,
+        None => return None,
+    };
+    counters::count_git_lookup();
+    let head = git.repo.refname_to_id("HEAD").and_then(|oid| git.repo.find_commit(oid));
+    let tree = match head.and_then(|commit| commit.tree()) {
+        Ok(tree) => tree,
+        Err(_) => return Some(false),
+    };
+    let is_file = match tree.get_path(Path::new(path)) {
+        Ok(entry) => entry.kind() == Some(git2::ObjectType::Blob),
+        Err(_) => false,
+    };
+    Some(is_file)
+}
 
-    let git = try!(config::get_git(tree_config));
-    let hg = match git.hg_map.get(&commit.id()) {
-        Some(hg_id) => {
-            let hg_link = format!("<a href=\"https://hg.mozilla.org/mozilla-central/rev/{}\">{}</a>", hg_id, hg_id);
-            vec![F::T(format!("<tr><td>hg</td><td>{}</td></tr>", hg_link))]
-        },
+// Number of commits shown on each page of a file's log.
+const LOG_PAGE_SIZE: usize = 50;
 
-        None => vec![]
-    };
+// Stop walking history for a log page after this many commits.
+const MAX_LOG_COMMITS: usize = 50000;
 
-    let git = format!("<a href=\"https://github.com/mozilla/gecko-dev/commit/{}\">{}</a>",
-                      commit.id(), commit.id());
+struct LogEntry<'a> {
+    commit: git2::Commit<'a>,
+    path: String,
+    renamed_from: Option<String>,
+}
 
-    let naive_t = NaiveDateTime::from_timestamp(commit.time().seconds(), 0);
-    let tz = FixedOffset::east(commit.time().offset_minutes() * 60);
-    let t : DateTime<FixedOffset> = DateTime::from_utc(naive_t, tz);
-    let t = t.to_rfc2822();
+fn path_id(commit: &git2::Commit, path: &str) -> Option<git2::Oid> {
+    match commit.tree() {
+        Ok(tree) => tree.get_path(Path::new(path)).ok().map(|entry| entry.id()),
+        Err(_) => None,
+    }
+}
 
-    let f = F::Seq(vec![
-        F::T(format!("<h3>{}</h3>", header)),
-        F::T(format!("<pre><code>{}</code></pre>", remainder)),
+// Returns the path `path` had in `parent` if `commit` renamed it.
+fn find_rename(repo: &git2::Repository,
+               parent: &git2::Commit,
+               commit: &git2::Commit,
+               path: &str) -> Option<String> {
+    match (parent.tree(), commit.tree()) {
+        (Ok(old_tree), Ok(new_tree)) => find_tree_rename(repo, &old_tree, &new_tree, path),
+        _ => None,
+    }
+}
 
-        F::S("<table>"),
-        F::Indent(vec![
-            F::T(format!("<tr><td>commit</td><td>{}</td></tr>", format_rev(tree_name, commit.id()))),
+// Returns the path in `old_tree` of the file that was renamed to `path`
+// in `new_tree`. Only renames are detected, not copies, so this stays
+// cheap for trees that are far apart.
+fn find_tree_rename(repo: &git2::Repository,
+                    old_tree: &git2::Tree,
+                    new_tree: &git2::Tree,
+                    path: &str) -> Option<String> {
+    let mut diff = match repo.diff_tree_to_tree(Some(old_tree), Some(new_tree), None) {
+        Ok(diff) => diff,
+        Err(_) => return None,
+    };
+    let mut find_opts = git2::DiffFindOptions::new();
+    find_opts.renames(true);
+    if diff.find_similar(Some(&mut find_opts)).is_err() {
+        return None;
+    }
+
+    for delta in diff.deltas() {
+        if delta.status() != git2::Delta::Renamed {
+            continue;
+        }
+        let new_path = delta.new_file().path().and_then(|p| p.to_str());
+        if new_path == Some(path) {
+            return delta.old_file().path().and_then(|p| p.to_str()).map(|p| p.to_owned());
+        }
+    }
+    None
+}
+
+// Finds where line `lineno` of `path` at `rev` was in the first parent
+// of `rev`, following renames. Lines that `rev` changed map to the
+// line they replaced, and added lines to the line before them. Returns
+// the parent revision, path and line.
+pub fn find_previous_line(cfg: &config::Config,
+                          tree_name: &str,
+                          rev: &str,
+                          path: &str,
+                          lineno: usize) -> Result<(String, String, usize), &'static str> {
+    let tree_config = try!(cfg.trees.get(tree_name).ok_or("Invalid tree"));
+    let git = try!(config::get_git(tree_config));
+    counters::count_git_lookup();
+    let commit_obj = try!(git.repo.revparse_single(rev).map_err(|_| "Bad revision"));
+    let commit = try!(commit_obj.as_commit().ok_or("Bad revision"));
+    let parent = try!(commit.parent(0).map_err(|_| "Revision has no parent"));
+
+    let parent_path = find_rename(&git.repo, &parent, &commit, path).unwrap_or(path.to_owned());
+
+    let blob_id = try!(path_id(&commit, path).ok_or("File not found"));
+    let parent_blob_id = try!(path_id(&parent, &parent_path).ok_or("File did not exist before this revision"));
+    counters::count_git_lookup();
+    let blob = try!(git.repo.find_blob(blob_id).map_err(|_| "Invalid path; expected file"));
+    let parent_blob = try!(git.repo.find_blob(parent_blob_id).map_err(|_| "Invalid path; expected file"));
+
+    let mut parent_lineno = 1;
+    for (new_line, old_line) in blame::map_lines(&parent_blob, &blob, true) {
+        if new_line + 1 > lineno {
+            break;
+        }
+        parent_lineno = old_line + 1;
+    }
+
+    Ok((parent.id().to_string(), parent_path, parent_lineno))
+}
+
+// Walks back from `commit` collecting the commits that changed
+// `path`, following renames. Returns at most `limit` entries.
+fn find_log_entries<'a>(repo: &'a git2::Repository,
+                        commit: &git2::Commit,
+                        path: &str,
+                        limit: usize) -> Vec<LogEntry<'a>> {
+    let mut entries = Vec::new();
+
+    // The path the file has in each commit still to be visited. Each
+    // commit passes its path on to the parents that have the file, so a
+    // rename on one side of a merge doesn't affect the other side. The
+    // walk is topological so that children are visited before parents.
+    let mut paths = HashMap::new();
+    paths.insert(commit.id(), path.to_owned());
+
+    let mut walk = match repo.revwalk() {
+        Ok(walk) => walk,
+        Err(_) => return entries,
+    };
+    walk.set_sorting(git2::Sort::TOPOLOGICAL);
+    if walk.push(commit.id()).is_err() {
+        return entries;
+    }
+
+    for oid in walk.take(MAX_LOG_COMMITS) {
+        if entries.len() >= limit {
+            break;
+        }
+
+        counters::count_git_lookup();
+        let c = match oid.and_then(|oid| repo.find_commit(oid)) {
+            Ok(c) => c,
+            Err(_) => break,
+        };
+        let path = match paths.remove(&c.id()) {
+            Some(path) => path,
+            None => continue,
+        };
+
+        let parents = c.parents().collect::<Vec<_>>();
+        let id = path_id(&c, &path);
+        let parent_ids = parents.iter().map(|p| path_id(p, &path)).collect::<Vec<_>>();
+        let changed = id.is_some() && parent_ids.iter().all(|p| *p != id);
+
+        let renamed_from = if changed && parent_ids.iter().all(|p| p.is_none()) && parents.len() > 0 {
+            find_rename(repo, &parents[0], &c, &path)
+        } else {
+            None
+        };
+
+        for (i, parent) in parents.iter().enumerate() {
+            let parent_path = match renamed_from {
+                Some(ref old_path) if i == 0 => old_path.clone(),
+                _ => path.clone(),
+            };
+            if parent_ids[i].is_some() || parent_path != path {
+                paths.entry(parent.id()).or_insert(parent_path);
+            }
+        }
+
+        if changed {
+            entries.push(LogEntry {
+                commit: c,
+                path: path,
+                renamed_from: renamed_from,
+            });
+        }
+    }
+
+    entries
+}
+
+pub fn format_log(cfg: &config::Config,
+                  tree_name: &str,
+                  rev: &str,
+                  path: &str,
+                  page: usize,
+                  writer: &mut Write) -> Result<(), &'static str> {
+    let tree_config = try!(cfg.trees.get(tree_name).ok_or("Invalid tree"));
+    let git = try!(config::get_git(tree_config));
+    counters::count_git_lookup();
+    let commit_obj = try!(git.repo.revparse_single(rev).map_err(|_| "Bad revision"));
+    let commit = try!(commit_obj.as_commit().ok_or("Bad revision"));
+    try!(path_id(&commit, path).ok_or("File not found"));
+
+    let page = cmp::max(1, cmp::min(page, MAX_LOG_COMMITS / LOG_PAGE_SIZE));
+    let mut entries = find_log_entries(&git.repo, &commit, path, page * LOG_PAGE_SIZE + 1);
+    let has_more = entries.len() > page * LOG_PAGE_SIZE;
+    entries.truncate(page * LOG_PAGE_SIZE);
+    let first = cmp::min((page - 1) * LOG_PAGE_SIZE, entries.len());
+    let entries = entries.split_off(first);
+
+    let commit_id = commit.id().to_string();
+    let (header, _) = try!(blame::commit_header(&commit, &tree_config.commit_links));
+
+    let filename = path.rsplit('/').next().unwrap();
+    let title = format!("{} log - mozsearch", filename);
+    let opt = Options {
+        title: &title,
+        tree_name: tree_name,
+        include_date: true,
+        revision: Some((&commit_id, &header)),
+    };
+
+    try!(output::generate_header(&opt, writer));
+
+    try!(output::generate_breadcrumbs(&opt, writer, path));
+
+    let sections = vec![PanelSection {
+        name: "Revision control".to_owned(),
+        items: vec![PanelItem {
+            title: "Show file".to_owned(),
+            link: format!("/{}/rev/{}/{}", tree_name, rev, path),
+            update_link_lineno: false,
+        }, PanelItem {
+            title: "Go to latest version".to_owned(),
+            link: format!("/{}/source/{}", tree_name, path),
+            update_link_lineno: false,
+        }],
+    }];
+    try!(output::generate_panel(writer, &sections));
+
+    let mut rows = vec![];
+    for entry in &entries {
+        let c = &entry.commit;
+        let id = c.id().to_string();
+        let (header, _) = try!(blame::commit_header(c, &tree_config.commit_links));
+
+        let naive_t = NaiveDateTime::from_timestamp(c.time().seconds(), 0);
+        let tz = FixedOffset::east(c.time().offset_minutes() * 60);
+        let t : DateTime<FixedOffset> = DateTime::from_utc(naive_t, tz);
+
+        let author = c.author();
+        let author = entity_replace(author.name().unwrap_or(""));
+
+        let renamed = match entry.renamed_from {
+            Some(ref old_path) => format!(" (renamed from {})", entity_replace(old_path)),
+            None => "".to_owned(),
+        };
+        let entry_path = entity_replace(&entry.path);
+
+        rows.push(F::Seq(vec![
+            F::S("<tr>"),
+            F::Indent(vec![
+                F::T(format!("<td><a href=\"/{}/commit/{}\">{}</a></td>", tree_name, id, &id[..8])),
+                F::T(format!("<td>{}</td>", t.format("%Y-%m-%d %H:%M"))),
+                F::T(format!("<td>{}</td>", author)),
+                F::T(format!("<td>{}{}</td>", header, renamed)),
+                F::T(format!("<td><a href=\"/{}/diff/{}/{}\">diff</a></td>", tree_name, id, entry_path)),
+                F::T(format!("<td><a href=\"/{}/rev/{}/{}\">file</a></td>", tree_name, id, entry_path)),
+            ]),
+            F::S("</tr>"),
+        ]));
+    }
+
+    let page_url = entity_replace(&format!("/{}/log/{}/{}", tree_name, rev, path));
+    let mut pages = vec![];
+    if page > 1 {
+        pages.push(F::T(format!("<a href=\"{}?page={}\">Newer</a>", page_url, page - 1)));
+    }
+    if has_more {
+        pages.push(F::T(format!("<a href=\"{}?page={}\">Older</a>", page_url, page + 1)));
+    }
+
+    let f = F::Seq(vec![
+        F::S("<table class=\"log\">"),
+        F::Indent(vec![
+            F::S("<thead>"),
+            F::Indent(vec![
+                F::S("<tr>"),
+                F::Indent(vec![
+                    F::S("<th scope=\"col\">Commit</th>"),
+                    F::S("<th scope=\"col\">Date</th>"),
+                    F::S("<th scope=\"col\">Author</th>"),
+                    F::S("<th scope=\"col\">Description</th>"),
+                    F::S("<th scope=\"col\"></th>"),
+                    F::S("<th scope=\"col\"></th>"),
+                ]),
+                F::S("</tr>"),
+            ]),
+            F::S("</thead>"),
+            F::S("<tbody>"),
+            F::Indent(rows),
+            F::S("</tbody>"),
+        ]),
+        F::S("</table>"),
+        F::S("<div class=\"log-pages\">"),
+        F::Indent(pages),
+        F::S("</div>"),
+    ]);
+
+    try!(output::generate_formatted(writer, &f, 0));
+
+    output::generate_footer(&opt, tree_name, path, writer).unwrap();
+
+    Ok(())
+}
+
+fn split_lines(s: &str) -> Vec<&str> {
+    let mut split = s.split('\n').collect::<Vec<_>>();
+    if split[split.len() - 1].len() == 0 {
+        split.pop();
+    }
+    split
+}
+
+// A line of a combined diff: a '-', '+' or ' ' for each parent, saying
+// how the line differs from that parent, and the line's text.
+type CombinedLine = (Vec<char>, String);
+
+// Diffs `path` in `commit` against each of its parents, with the whole
+// file as context, like `git diff-tree --cc -U100000`. Returns None if
+// the file is the same as in one of the parents (or there are no
+// parents), or if no line was added or removed relative to every
+// parent, since then the merge only took changes from the parents.
+fn combined_diff(repo: &git2::Repository, commit: &git2::Commit, path: &str) -> Result<Option<Vec<CombinedLine>>, &'static str> {
+    let read_file = |commit: &git2::Commit| {
+        counters::count_git_lookup();
+        match path_id(commit, path).and_then(|id| repo.find_blob(id).ok()) {
+            Some(blob) => blob.content().to_vec(),
+            None => Vec::new(),
+        }
+    };
+
+    let new_data = read_file(commit);
+    let parent_data = commit.parents().map(|parent| read_file(&parent)).collect::<Vec<_>>();
+    if parent_data.is_empty() || parent_data.iter().any(|data| *data == new_data) {
+        return Ok(None);
+    }
+
+    let new_text = decode_bytes(new_data.clone());
+    let new_lines = split_lines(&new_text);
+    let num_parents = parent_data.len();
+
+    // For each line of the new file, whether it was added relative to
+    // each parent, and the lines removed just before it.
+    let mut added = vec![vec![false; num_parents]; new_lines.len()];
+    let mut lost = vec![Vec::new(); new_lines.len() + 1];
+
+    for (parent, data) in parent_data.iter().enumerate() {
+        let mut opts = git2::DiffOptions::new();
+        opts.patience(true).context_lines(100000);
+        let patch = try!(git2::Patch::from_buffers(data, Some(Path::new(path)),
+                                                   &new_data, Some(Path::new(path)),
+                                                   Some(&mut opts)).map_err(|_| "Diff failed"));
+
+        let mut removed = vec![Vec::new(); new_lines.len() + 1];
+        for h in 0 .. patch.num_hunks() {
+            let (hunk, num_lines) = try!(patch.hunk(h).map_err(|_| "Diff failed"));
+            let mut pos = (hunk.new_start() as usize).saturating_sub(1);
+            for l in 0 .. num_lines {
+                let line = try!(patch.line_in_hunk(h, l).map_err(|_| "Diff failed"));
+                match (line.origin(), line.new_lineno()) {
+                    ('-', _) => {
+                        let content = decode_bytes(line.content().to_vec());
+                        removed[pos].push(content.trim_right_matches('\n').to_owned());
+                    },
+                    ('+', Some(lineno)) => {
+                        added[lineno as usize - 1][parent] = true;
+                        pos = lineno as usize;
+                    },
+                    (' ', Some(lineno)) => pos = lineno as usize,
+                    _ => {},
+                }
+            }
+        }
+
+        for (lost, removed) in lost.iter_mut().zip(removed) {
+            coalesce_lost_lines(lost, removed, parent, num_parents);
+        }
+    }
+
+    let all = |flags: &Vec<bool>| flags.iter().all(|&flag| flag);
+    if !added.iter().any(&all) && !lost.iter().any(|lines| lines.iter().any(|&(_, ref flags)| all(flags))) {
+        return Ok(None);
+    }
+
+    let origin = |flags: &[bool], c: char| flags.iter().map(|&flag| if flag { c } else { ' ' }).collect::<Vec<_>>();
+    let mut result = Vec::new();
+    for (i, lines) in lost.into_iter().enumerate() {
+        for (content, flags) in lines {
+            result.push((origin(&flags, '-'), content));
+        }
+        if i < new_lines.len() {
+            result.push((origin(&added[i], '+'), new_lines[i].to_owned()));
+        }
+    }
+    Ok(Some(result))
+}
+
+// Merges the lines removed from `parent` at some point in a file into
+// those removed from earlier parents at the same point, so that a line
+// removed from several parents is shown once.
+fn coalesce_lost_lines(lost: &mut Vec<(String, Vec<bool>)>, removed: Vec<String>, parent: usize, num_parents: usize) {
+    if removed.is_empty() {
+        return;
+    }
+
+    let old = ::std::mem::replace(lost, Vec::new());
+    let (n, m) = (old.len(), removed.len());
+    let width = m + 1;
+    let mut lengths = vec![0; (n + 1) * width];
+    for i in (0 .. n).rev() {
+        for j in (0 .. m).rev() {
+            lengths[i * width + j] = if old[i].0 == removed[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                cmp::max(lengths[(i + 1) * width + j], lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i].0 == removed[j] {
+            let mut line = old[i].clone();
+            line.1[parent] = true;
+            lost.push(line);
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && lengths[(i + 1) * width + j] >= lengths[i * width + j + 1]) {
+            lost.push(old[i].clone());
+            i += 1;
+        } else {
+            let mut flags = vec![false; num_parents];
+            flags[parent] = true;
+            lost.push((removed[j].clone(), flags));
+            j += 1;
+        }
+    }
+}
+
+// Reads the blame for `path` as of `rev`, with ignored revisions
+// skipped. Returns None if the file doesn't exist in `rev`.
+fn read_blame_lines(git: &config::GitData, rev: git2::Oid, path: &str) -> Result<Option<Vec<String>>, &'static str> {
+    let blame_oid = try!(git.blame_map.get(&rev).ok_or("Unable to find blame"));
+    counters::count_blame_lookup();
+    let blame_commit = try!(git.blame_repo.find_commit(blame_oid).map_err(|_| "Blame is not a blob"));
+    let blame_tree = try!(blame_commit.tree().map_err(|_| "Bad revision"));
+    let blame_entry = match blame_tree.get_path(Path::new(path)) {
+        Ok(blame_entry) => blame_entry,
+        Err(_) => return Ok(None),
+    };
+
+    counters::count_blame_lookup();
+    let blame = read_blob_entry(&git.blame_repo, &blame_entry);
+    let mut blame_lines = Vec::new();
+    for line in blame.lines() {
+        blame_lines.push(try!(blame::BlameLine::parse(line).ok_or("Bad blame data")));
+    }
+    blame::skip_ignored_revs(git, path, &mut blame_lines);
+    Ok(Some(blame_lines.iter().map(|line| line.to_string()).collect()))
+}
+
+// Returns how to highlight `path` in a diff. Documentation files are
+// shown as their source text, since a diff of the rendered output
+// wouldn't line up with the change.
+fn diff_formatting(path: &str) -> Result<FormatAs, &'static str> {
+    match languages::select_formatting(path) {
+        FormatAs::Binary => Err("Cannot diff binary file"),
+        FormatAs::FormatDoc(_) => Ok(FormatAs::Plain),
+        format => Ok(format),
+    }
+}
+
+pub fn format_diff(cfg: &config::Config,
+                   tree_name: &str,
+                   rev: &str,
+                   path: &str,
+                   side_by_side: bool,
+                   writer: &mut Write) -> Result<(), &'static str> {
+    let tree_config = try!(cfg.trees.get(tree_name).ok_or("Invalid tree"));
+
+    let git = try!(config::get_git(tree_config));
+    counters::count_git_lookup();
+    let commit_obj = try!(git.repo.revparse_single(rev).map_err(|_| "Bad revision"));
+    let commit = try!(commit_obj.as_commit().ok_or("Bad revision"));
+
+    let diff_lines = match try!(combined_diff(&git.repo, commit, path)) {
+        Some(diff_lines) => diff_lines,
+        None => return format_path(cfg, tree_name, rev, path, false, writer).map(|_| ()),
+    };
+
+    let mut blames = Vec::new();
+    for parent_oid in commit.parent_ids() {
+        blames.push(try!(read_blame_lines(git, parent_oid, path)));
+    }
+
+    let mut new_lineno = 1;
+    let mut old_lineno = commit.parent_ids().map(|_| 1).collect::<Vec<_>>();
+
+    let mut new_lines = String::new();
+
+    let mut output = Vec::new();
+    for &(ref origin, ref content) in &diff_lines {
+        let num_parents = origin.len();
+        let content = &content[..];
+        let mut cur_blame = None;
+        for i in 0..num_parents {
+            let has_minus = origin.contains(&'-');
+            if (has_minus && origin[i] == '-') ||
+                (!has_minus && origin[i] != '+')
+            {
+                cur_blame = match blames[i] {
+                    Some(ref lines) => Some(&lines[old_lineno[i] - 1]),
+                    None => panic!("expected blame for '-' line, none found"),
+                };
+                old_lineno[i] += 1;
+            }
+        }
+
+        let mut lno = -1;
+        if !origin.contains(&'-') {
+            new_lines.push_str(content);
+            new_lines.push('\n');
+
+            lno = new_lineno;
+            new_lineno += 1;
+        }
+
+        output.push((lno, cur_blame, origin.clone(), content));
+    }
+
+    let format = try!(diff_formatting(path));
+    let jumps : HashMap<String, analysis::Jump> = HashMap::new();
+    let analysis = Vec::new();
+    let (formatted_lines, _) = format_code(&jumps, format, path, &new_lines, &analysis);
+
+    // Side-by-side diffs are only shown against a single parent.
+    let side_by_side = side_by_side && blames.len() == 1;
+    let mut old_formatted_lines = Vec::new();
+    let mut child_blame = Vec::new();
+    if side_by_side {
+        let mut old_lines = String::new();
+        for &(_, _, ref origin, content) in &output {
+            if origin[0] != '+' {
+                old_lines.push_str(content);
+                old_lines.push('\n');
+            }
+        }
+        old_formatted_lines = format_code(&jumps, try!(diff_formatting(path)), path, &old_lines, &analysis).0;
+        child_blame = try!(read_blame_lines(git, commit.id(), path)).unwrap_or(vec![]);
+    }
+
+    let (header, _) = try!(blame::commit_header(&commit, &tree_config.commit_links));
+
+    let filename = Path::new(path).file_name().unwrap().to_str().unwrap();
+    let title = format!("{} - mozsearch", filename);
+    let opt = Options {
+        title: &title,
+        tree_name: tree_name,
+        include_date: true,
+        revision: Some((rev, &header)),
+    };
+
+    try!(output::generate_header(&opt, writer));
+
+    let mut sections = vec![PanelSection {
+        name: "Revision control".to_owned(),
+        items: vec![PanelItem {
+            title: "Show changeset".to_owned(),
+            link: format!("/{}/commit/{}", tree_name, rev),
+            update_link_lineno: false,
+        }, PanelItem {
+            title: "Show file without diff".to_owned(),
+            link: format!("/{}/rev/{}/{}", tree_name, rev, path),
+            update_link_lineno: true,
+        }, PanelItem {
+            title: "Go to latest version".to_owned(),
+            link: format!("/{}/source/{}", tree_name, path),
+            update_link_lineno: true,
+        }, PanelItem {
+            title: "Log".to_owned(),
+            link: log_url(tree_name, &tree_config.urls, rev, path),
+            update_link_lineno: false,
+        }],
+    }];
+    if blames.len() == 1 {
+        sections[0].items.push(diff_view_toggle(side_by_side));
+    }
+    try!(output::generate_panel(writer, &sections));
+
+    if side_by_side {
+        let parent_blame = blames[0].as_ref().map(|lines| &lines[..]).unwrap_or(&[]);
+        generate_side_by_side_table(&output, parent_blame, &child_blame,
+                                    &old_formatted_lines, &formatted_lines, writer);
+    } else {
+        generate_diff_table(&output, &formatted_lines, writer);
+    }
+
+    output::generate_footer(&opt, tree_name, path, writer).unwrap();
+
+    Ok(())
+}
+
+// Links to the other of the unified and side-by-side views of a diff.
+fn diff_view_toggle(side_by_side: bool) -> PanelItem {
+    if side_by_side {
+        PanelItem {
+            title: "Unified view".to_owned(),
+            link: "?".to_owned(),
+            update_link_lineno: true,
+        }
+    } else {
+        PanelItem {
+            title: "Side-by-side view".to_owned(),
+            link: "?view=side-by-side".to_owned(),
+            update_link_lineno: true,
+        }
+    }
+}
+
+// Shows what changed between two revisions. For a file, this is the
+// whole file with the changes marked and blame from both revisions;
+// for a directory (or the whole tree) it lists the files that changed.
+pub fn format_compare(cfg: &config::Config,
+                      tree_name: &str,
+                      rev1: &str,
+                      rev2: &str,
+                      path: &str,
+                      side_by_side: bool,
+                      writer: &mut Write) -> Result<(), &'static str> {
+    let tree_config = try!(cfg.trees.get(tree_name).ok_or("Invalid tree"));
+    let git = try!(config::get_git(tree_config));
+
+    counters::count_git_lookup();
+    let old_obj = try!(git.repo.revparse_single(rev1).map_err(|_| "Bad revision"));
+    let old_commit = try!(old_obj.as_commit().ok_or("Bad revision"));
+    counters::count_git_lookup();
+    let new_obj = try!(git.repo.revparse_single(rev2).map_err(|_| "Bad revision"));
+    let new_commit = try!(new_obj.as_commit().ok_or("Bad revision"));
+    let old_tree = try!(old_commit.tree().map_err(|_| "Bad revision"));
+    let new_tree = try!(new_commit.tree().map_err(|_| "Bad revision"));
+
+    let path = path.trim_right_matches('/');
+    let (old_entry, new_entry) = if path.is_empty() {
+        (None, None)
+    } else {
+        (old_tree.get_path(Path::new(path)).ok(), new_tree.get_path(Path::new(path)).ok())
+    };
+    let is_dir = |entry: &Option<git2::TreeEntry>| match *entry {
+        Some(ref entry) => entry.kind() == Some(git2::ObjectType::Tree),
+        None => false,
+    };
+
+    let old_id = old_commit.id().to_string();
+    let new_id = new_commit.id().to_string();
+    let desc = format!("Changes since <a href=\"/{}/commit/{}\">{}</a>", tree_name, old_id, &old_id[..8]);
+    let title = match Path::new(path).file_name().and_then(|name| name.to_str()) {
+        Some(filename) => format!("{} - mozsearch", filename),
+        None => format!("{}..{} - mozsearch", rev1, rev2),
+    };
+    let opt = Options {
+        title: &title,
+        tree_name: tree_name,
+        include_date: true,
+        revision: Some((&new_id, &desc)),
+    };
+
+    if path.is_empty() || is_dir(&old_entry) || is_dir(&new_entry) {
+        let changes = try!(tree_changes(&git.repo, Some(&old_tree), &new_tree, path, &[]));
+        let links = changes.iter().map(|change| {
+            if change.status == 'D' {
+                format!("/{}/rev/{}/{}", tree_name, old_id, change.old_path)
+            } else {
+                format!("/{}/compare/{}..{}/{}", tree_name, old_id, new_id, change.path)
+            }
+        }).collect::<Vec<_>>();
+
+        try!(output::generate_header(&opt, writer));
+        try!(output::generate_breadcrumbs(&opt, writer, path));
+        try!(generate_diffstat(&changes, &links, "", writer));
+        output::generate_footer(&opt, tree_name, path, writer).unwrap();
+        return Ok(());
+    }
+
+    if old_entry.is_none() && new_entry.is_none() {
+        return Err("File not found");
+    }
+
+    // If the file was renamed since `rev1`, compare it with the file it
+    // came from.
+    let mut old_path = path.to_owned();
+    let mut old_entry = old_entry;
+    if old_entry.is_none() {
+        counters::count_git_lookup();
+        if let Some(renamed_from) = find_tree_rename(&git.repo, &old_tree, &new_tree, path) {
+            old_entry = old_tree.get_path(Path::new(&renamed_from)).ok();
+            old_path = renamed_from;
+        }
+    }
+
+    let format = try!(diff_formatting(path));
+
+    let read_entry = |entry: &Option<git2::TreeEntry>| match *entry {
+        Some(ref entry) => {
+            counters::count_git_lookup();
+            git.repo.find_blob(entry.id()).map(|blob| blob.content().to_vec()).map_err(|_| "Invalid path; expected file")
+        },
+        None => Ok(Vec::new()),
+    };
+    let old_data = try!(read_entry(&old_entry));
+    let new_data = try!(read_entry(&new_entry));
+
+    let mut opts = git2::DiffOptions::new();
+    opts.patience(true).context_lines(100000);
+    let patch = try!(git2::Patch::from_buffers(&old_data, Some(Path::new(&old_path)),
+                                               &new_data, Some(Path::new(path)),
+                                               Some(&mut opts)).map_err(|_| "Diff failed"));
+    if patch.num_hunks() == 0 {
+        return format_path(cfg, tree_name, &new_id, path, false, writer).map(|_| ());
+    }
+
+    let mut lines = Vec::new();
+    for h in 0 .. patch.num_hunks() {
+        for l in 0 .. patch.num_lines_in_hunk(h).unwrap_or(0) {
+            let line = try!(patch.line_in_hunk(h, l).map_err(|_| "Diff failed"));
+            let content = decode_bytes(line.content().to_vec());
+            lines.push((line.origin(), line.old_lineno(), content.trim_right_matches('\n').to_owned()));
+        }
+    }
+
+    let old_blame = try!(read_blame_lines(git, old_commit.id(), &old_path)).unwrap_or(vec![]);
+    // Blame gives the file's own path as `%`, which would be taken to
+    // mean the new path.
+    let old_blame = if old_path == path {
+        old_blame
+    } else {
+        old_blame.iter().filter_map(|line| blame::BlameLine::parse(line)).map(|mut line| {
+            if line.path == "%" {
+                line.path = old_path.clone();
+            }
+            line.to_string()
+        }).collect()
+    };
+    let new_blame = try!(read_blame_lines(git, new_commit.id(), path)).unwrap_or(vec![]);
+
+    let mut new_lineno = 1;
+    let mut new_lines = String::new();
+    let mut old_lines = String::new();
+    let mut output = Vec::new();
+    for &(origin, old_lineno, ref content) in &lines {
+        if origin == '-' || origin == ' ' {
+            old_lines.push_str(content);
+            old_lines.push('\n');
+        }
+
+        let (lno, blame) = match origin {
+            '-' => (-1, old_lineno.and_then(|n| old_blame.get(n as usize - 1))),
+            '+' | ' ' => {
+                new_lines.push_str(content);
+                new_lines.push('\n');
+                new_lineno += 1;
+                (new_lineno - 1, new_blame.get(new_lineno as usize - 2))
+            },
+            _ => continue,
+        };
+        output.push((lno, blame, vec![origin], &content[..]));
+    }
+
+    let jumps : HashMap<String, analysis::Jump> = HashMap::new();
+    let (formatted_lines, _) = format_code(&jumps, format, path, &new_lines, &[]);
+    let old_formatted_lines = if side_by_side {
+        let old_format = match languages::select_formatting(&old_path) {
+            FormatAs::Binary | FormatAs::FormatDoc(_) => FormatAs::Plain,
+            format => format,
+        };
+        format_code(&jumps, old_format, &old_path, &old_lines, &[]).0
+    } else {
+        Vec::new()
+    };
+
+    try!(output::generate_header(&opt, writer));
+
+    let sections = vec![PanelSection {
+        name: "Revision control".to_owned(),
+        items: vec![PanelItem {
+            title: "Show changed files".to_owned(),
+            link: format!("/{}/compare/{}..{}/", tree_name, old_id, new_id),
+            update_link_lineno: false,
+        }, PanelItem {
+            title: format!("Show file at {}", &old_id[..8]),
+            link: format!("/{}/rev/{}/{}", tree_name, old_id, old_path),
+            update_link_lineno: false,
+        }, PanelItem {
+            title: format!("Show file at {}", &new_id[..8]),
+            link: format!("/{}/rev/{}/{}", tree_name, new_id, path),
+            update_link_lineno: true,
+        }, PanelItem {
+            title: "Log".to_owned(),
+            link: log_url(tree_name, &tree_config.urls, &new_id, path),
+            update_link_lineno: false,
+        }, diff_view_toggle(side_by_side)],
+    }];
+    try!(output::generate_panel(writer, &sections));
+
+    if side_by_side {
+        generate_side_by_side_table(&output, &old_blame, &new_blame,
+                                    &old_formatted_lines, &formatted_lines, writer);
+    } else {
+        generate_diff_table(&output, &formatted_lines, writer);
+    }
+
+    output::generate_footer(&opt, tree_name, path, writer).unwrap();
+
+    Ok(())
+}
+
+// A line of a diff: its line number in the new file (or -1 if it was
+// removed), its blame, its origin in each parent, and its text.
+type DiffLine<'a> = (i32, Option<&'a String>, Vec<char>, &'a str);
+
+// Writes a line number and blame strip for each line of one side of a
+// diff. Lines numbered 0 or less get no number. Only lines in the new
+// file get ids, so that links to them work.
+fn generate_diff_line_numbers(lines: &[(i32, Option<&String>)], with_ids: bool, writer: &mut Write) {
+    let mut last_rev = None;
+    let mut last_color = false;
+    for &(lineno, blame) in lines {
+        let blame_data = match blame.and_then(|line| blame::BlameLine::parse(line)) {
+            Some(blame_line) => {
+                let color = if last_rev.as_ref() == Some(&blame_line.rev) { last_color } else { !last_color };
+                last_color = color;
+                let data = blame_strip_attrs(&blame_line, color, None);
+                last_rev = Some(blame_line.rev);
+                data
+            },
+            None => "".to_owned(),
+        };
+
+        let line_str = if lineno > 0 && with_ids {
+            format!("<span id=\"l{}\" class=\"line-number\">{}", lineno, lineno)
+        } else if lineno > 0 {
+            format!("<span class=\"line-number\">{}", lineno)
+        } else {
+            "<span class=\"line-number\">&nbsp;".to_owned()
+        };
+        let f = F::Seq(vec![
+            F::T(line_str),
+            F::T(format!("<div{}></div>", blame_data)),
+            F::S("</span>")
+        ]);
+
+        output::generate_formatted(writer, &f, 0).unwrap();
+    }
+}
+
+// Pairs each run of removed lines with the run of added lines after it
+// and finds the words that changed within each pair. Returns the
+// ranges of chars that changed in each line of `output`.
+fn intra_line_changes(output: &[DiffLine]) -> Vec<Vec<(usize, usize)>> {
+    let is_removed = |i: usize| output[i].2.contains(&'-');
+    let is_added = |i: usize| !output[i].2.contains(&'-') && output[i].2.contains(&'+');
+
+    let mut changes = vec![Vec::new(); output.len()];
+    let mut i = 0;
+    while i < output.len() {
+        let removed_start = i;
+        while i < output.len() && is_removed(i) {
+            i += 1;
+        }
+        let added_start = i;
+        while i < output.len() && is_added(i) {
+            i += 1;
+        }
+        if i == removed_start {
+            i += 1;
+            continue;
+        }
+
+        for k in 0 .. cmp::min(added_start - removed_start, i - added_start) {
+            let (old, new) = (removed_start + k, added_start + k);
+            if let Some((old_changes, new_changes)) = word_diff::line_changes(output[old].3, output[new].3) {
+                changes[old] = old_changes;
+                changes[new] = new_changes;
+            }
+        }
+    }
+    changes
+}
+
+// Writes the table of line numbers, blame and code for a diff. Lines
+// that are in the new file use their entry in `formatted_lines`.
+fn generate_diff_table(output: &[DiffLine], formatted_lines: &[String], writer: &mut Write) {
+    let f = F::Seq(vec![
+        F::S("<table id=\"file\" class=\"file\">"),
+        F::Indent(vec![
+            F::S("<thead class=\"visually-hidden\">"),
+            F::Indent(vec![
+                F::S("<th scope=\"col\">Line</th>"),
+                F::S("<th scope=\"col\">Code</th>"),
+            ]),
+            F::S("</thead>"),
+
+            F::S("<tbody>"),
+            F::Indent(vec![
+                F::S("<tr>"),
+                F::Indent(vec![
+                    F::S("<td id=\"line-numbers\">"),
+                ]),
+            ]),
+        ]),
+    ]);
+
+    output::generate_formatted(writer, &f, 0).unwrap();
+
+    let numbers = output.iter().map(|&(lineno, blame, _, _)| (lineno, blame)).collect::<Vec<_>>();
+    generate_diff_line_numbers(&numbers, true, writer);
+
+    let f = F::Seq(vec![
+        F::Indent(vec![
+            F::Indent(vec![
+                F::Indent(vec![
+                    F::S("</td>"),
+                    F::S("<td class=\"code\">"),
+                ]),
+            ]),
+        ]),
+    ]);
+    output::generate_formatted(writer, &f, 0).unwrap();
+
+    let changes = intra_line_changes(output);
+
+    write!(writer, "<pre>").unwrap();
+    for (i, &(lineno, _blame, ref origin, content)) in output.iter().enumerate() {
+        let content = entity_replace(content);
+        let content = if lineno > 0 && (lineno as usize) < formatted_lines.len() + 1 {
+            &formatted_lines[(lineno as usize) - 1]
+        } else {
+            &content
+        };
+        let marked;
+        let content = if changes[i].is_empty() {
+            content
+        } else {
+            marked = word_diff::mark_changes(content, &changes[i]);
+            &marked
+        };
+
+        let origin = origin.iter().cloned().collect::<String>();
+
+        let class = if origin.contains('-') {
+            " class=\"minus-line\""
+        } else if origin.contains('+') {
+            " class=\"plus-line\""
+        } else {
+            ""
+        };
+
+        write!(writer, "<code id=\"line-{}\" aria-labelledby=\"{}\"{}>", lineno, lineno, class).unwrap();
+        write!(writer, "{} {}", origin, content).unwrap();
+        write!(writer, "\n</code>").unwrap();
+    }
+    write!(writer, "</pre>").unwrap();
+
+    let f = F::Seq(vec![
+        F::Indent(vec![
+            F::Indent(vec![
+                F::Indent(vec![
+                    F::S("</td>"),
+                ]),
+                F::S("</tr>"),
+            ]),
+            F::S("</tbody>"),
+        ]),
+        F::S("</table>"),
+    ]);
+    output::generate_formatted(writer, &f, 0).unwrap();
+}
+
+// Writes a diff with the old file on the left and the new file on the
+// right. Runs of removed and added lines are paired up row by row, with
+// blank rows padding out the shorter side. Only the first parent of each
+// line in `output` is looked at.
+fn generate_side_by_side_table(output: &[DiffLine],
+                               old_blame: &[String],
+                               new_blame: &[String],
+                               old_formatted: &[String],
+                               new_formatted: &[String],
+                               writer: &mut Write) {
+    // Each row is the old and new line number (if any) and whether it
+    // changed.
+    let mut rows = Vec::new();
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+
+    fn pair_up(rows: &mut Vec<(Option<usize>, Option<usize>, bool)>,
+               removed: &mut Vec<usize>,
+               added: &mut Vec<usize>) {
+        for i in 0 .. cmp::max(removed.len(), added.len()) {
+            rows.push((removed.get(i).cloned(), added.get(i).cloned(), true));
+        }
+        removed.clear();
+        added.clear();
+    }
+
+    let mut old_text = Vec::new();
+    let mut new_text = Vec::new();
+    for &(_, _, ref origin, content) in output {
+        match origin[0] {
+            '-' => {
+                old_text.push(content);
+                removed.push(old_text.len());
+            },
+            '+' => {
+                new_text.push(content);
+                added.push(new_text.len());
+            },
+            _ => {
+                pair_up(&mut rows, &mut removed, &mut added);
+                old_text.push(content);
+                new_text.push(content);
+                rows.push((Some(old_text.len()), Some(new_text.len()), false));
+            },
+        }
+    }
+    pair_up(&mut rows, &mut removed, &mut added);
+
+    let changes = rows.iter().map(|&(old, new, changed)| {
+        match (old, new) {
+            (Some(old), Some(new)) if changed => word_diff::line_changes(old_text[old - 1], new_text[new - 1]),
+            _ => None,
+        }
+    }).collect::<Vec<_>>();
+    let mark = |content: &str, ranges: Option<&Vec<(usize, usize)>>| match ranges {
+        Some(ranges) => word_diff::mark_changes(content, ranges),
+        None => content.to_owned(),
+    };
+
+    fn number(lineno: Option<usize>, blame: &[String]) -> (i32, Option<&String>) {
+        match lineno {
+            Some(n) => (n as i32, blame.get(n - 1)),
+            None => (0, None),
+        }
+    }
+    let old_numbers = rows.iter().map(|&(old, _, _)| number(old, old_blame)).collect::<Vec<_>>();
+    let new_numbers = rows.iter().map(|&(_, new, _)| number(new, new_blame)).collect::<Vec<_>>();
+
+    let f = F::Seq(vec![
+        F::S("<table id=\"file\" class=\"file side-by-side\">"),
+        F::Indent(vec![
+            F::S("<thead class=\"visually-hidden\">"),
+            F::Indent(vec![
+                F::S("<th scope=\"col\">Line</th>"),
+                F::S("<th scope=\"col\">Old code</th>"),
+                F::S("<th scope=\"col\">Line</th>"),
+                F::S("<th scope=\"col\">New code</th>"),
+            ]),
+            F::S("</thead>"),
+
+            F::S("<tbody>"),
+            F::Indent(vec![
+                F::S("<tr>"),
+                F::Indent(vec![
+                    F::S("<td class=\"old-line-numbers\">"),
+                ]),
+            ]),
+        ]),
+    ]);
+    output::generate_formatted(writer, &f, 0).unwrap();
+
+    generate_diff_line_numbers(&old_numbers, false, writer);
+
+    let f = F::Indent(vec![
+        F::Indent(vec![
+            F::Indent(vec![
+                F::S("</td>"),
+                F::S("<td class=\"code\">"),
+            ]),
+        ]),
+    ]);
+    output::generate_formatted(writer, &f, 0).unwrap();
+
+    write!(writer, "<pre>").unwrap();
+    for (&(old, _, changed), row_changes) in rows.iter().zip(&changes) {
+        match old {
+            Some(n) => {
+                let class = if changed { " class=\"minus-line\"" } else { "" };
+                let content = old_formatted.get(n - 1).map(|s| &s[..]).unwrap_or("");
+                let content = mark(content, row_changes.as_ref().map(|&(ref old, _)| old));
+                write!(writer, "<code{}>{}\n</code>", class, content).unwrap();
+            },
+            None => write!(writer, "<code class=\"diff-padding\">\n</code>").unwrap(),
+        }
+    }
+    write!(writer, "</pre>").unwrap();
+
+    let f = F::Indent(vec![
+        F::Indent(vec![
+            F::Indent(vec![
+                F::S("</td>"),
+                F::S("<td id=\"line-numbers\">"),
+            ]),
+        ]),
+    ]);
+    output::generate_formatted(writer, &f, 0).unwrap();
+
+    generate_diff_line_numbers(&new_numbers, true, writer);
+
+    let f = F::Indent(vec![
+        F::Indent(vec![
+            F::Indent(vec![
+                F::S("</td>"),
+                F::S("<td class=\"code\">"),
+            ]),
+        ]),
+    ]);
+    output::generate_formatted(writer, &f, 0).unwrap();
+
+    write!(writer, "<pre>").unwrap();
+    for (&(_, new, changed), row_changes) in rows.iter().zip(&changes) {
+        match new {
+            Some(n) => {
+                let class = if changed { " class=\"plus-line\"" } else { "" };
+                let content = new_formatted.get(n - 1).map(|s| &s[..]).unwrap_or("");
+                let content = mark(content, row_changes.as_ref().map(|&(_, ref new)| new));
+                write!(writer, "<code id=\"line-{}\" aria-labelledby=\"{}\"{}>{}\n</code>", n, n, class, content).unwrap();
+            },
+            None => write!(writer, "<code class=\"diff-padding\">\n</code>").unwrap(),
+        }
+    }
+    write!(writer, "</pre>").unwrap();
+
+    let f = F::Seq(vec![
+        F::Indent(vec![
+            F::Indent(vec![
+                F::Indent(vec![
+                    F::S("</td>"),
+                ]),
+                F::S("</tr>"),
+            ]),
+            F::S("</tbody>"),
+        ]),
+        F::S("</table>"),
+    ]);
+    output::generate_formatted(writer, &f, 0).unwrap();
+}
+
+fn generate_commit_info(tree_name: &str,
+                        tree_config: &config::TreeConfig,
+                        writer: &mut Write,
+                        commit: &git2::Commit,
+                        inline_diffs: bool)  -> Result<(), &'static str> {
+    let (header, remainder) = try!(blame::commit_header(&commit, &tree_config.commit_links));
+
+    fn format_rev(tree_name: &str, oid: git2::Oid) -> String {
+        format!("<a href=\"/{}/commit/{}\">{}</a>", tree_name, oid, oid)
+    }
+
+    fn format_sig(sig: git2::Signature) -> String {
+        format!("{} &lt;{}>", sig.name().unwrap(), sig.email().unwrap())
+    }
+
+    let parents = commit.parent_ids().map(|p| {
+        F::T(format!("<tr><td>parent</td><td>{}</td></tr>", format_rev(tree_name, p)))
+    }).collect::<Vec<_>>();
+
+    let git = try!(config::get_git(tree_config));
+    let secondary_ids = git.blame_map.secondary_ids(&commit.id()).into_iter().map(|(vcs, id)| {
+        let template = tree_config.urls.mirror_rev.as_ref().and_then(|templates| templates.get(&vcs));
+        let link = match template {
+            Some(template) =>
+                format!("<a href=\"{}\">{}</a>",
+                        entity_replace(&config::expand_url(template, &[("rev", &id)])), entity_replace(&id)),
+            None => entity_replace(&id),
+        };
+        F::T(format!("<tr><td>{}</td><td>{}</td></tr>", entity_replace(&vcs), link))
+    }).collect::<Vec<_>>();
+
+    let git = match tree_config.urls.commit {
+        Some(ref template) => {
+            let id = commit.id().to_string();
+            let git_link = format!("<a href=\"{}\">{}</a>", config::expand_url(template, &[("rev", &id)]), id);
+            vec![F::T(format!("<tr><td>git</td><td>{}</td></tr>", git_link))]
+        },
+
+        None => vec![]
+    };
+
+    let naive_t = NaiveDateTime::from_timestamp(commit.time().seconds(), 0);
+    let tz = FixedOffset::east(commit.time().offset_minutes() * 60);
+    let t : DateTime<FixedOffset> = DateTime::from_utc(naive_t, tz);
+    let t = t.to_rfc2822();
+
+    let f = F::Seq(vec![
+        F::T(format!("<h3>{}</h3>", header)),
+        F::T(format!("<pre><code>{}</code></pre>", remainder)),
+
+        F::S("<table>"),
+        F::Indent(vec![
+            F::T(format!("<tr><td>commit</td><td>{}</td></tr>", format_rev(tree_name, commit.id()))),
             F::Seq(parents),
-            F::Seq(hg),
-            F::T(format!("<tr><td>git</td><td>{}</td></tr>", git)),
+            F::Seq(secondary_ids),
+            F::Seq(git),
             F::T(format!("<tr><td>author</td><td>{}</td></tr>", format_sig(commit.author()))),
             F::T(format!("<tr><td>committer</td><td>{}</td></tr>", format_sig(commit.committer()))),
             F::T(format!("<tr><td>commit time</td><td>{}</td></tr>", t)),
@@ -769,46 +2075,252 @@ fn generate_commit_info(tree_name: &str,
 
     try!(output::generate_formatted(writer, &f, 0));
 
-    let git_path = try!(config::get_git_path(tree_config));
-    let output = try!(Command::new("/usr/bin/git")
-                      .arg("show")
-                      .arg("--cc")
-                      .arg("--pretty=format:")
-                      .arg("--raw")
-                      .arg(format!("{}", commit.id()))
-                      .current_dir(&git_path)
-                      .output()
-                      .map_err(|_| "Diff failed 1"));
-    if !output.status.success() {
-        println!("ERR\n{}", decode_bytes(output.stderr));
-        return Err("Diff failed 2");
-    }
-    let difftxt = decode_bytes(output.stdout);
-
-    let lines = split_lines(&difftxt);
+    let git = try!(config::get_git(tree_config));
+    let changes = try!(commit_changes(&git.repo, commit));
+    let links = changes.iter().enumerate().map(|(i, change)| {
+        if inline_diffs {
+            format!("#diff-{}", i)
+        } else {
+            change_link(tree_name, commit, change)
+        }
+    }).collect::<Vec<_>>();
+    let toggle = if inline_diffs {
+        "<a href=\"?\">Hide diffs</a>"
+    } else {
+        "<a href=\"?diffs=inline\">Show all diffs</a>"
+    };
+    try!(generate_diffstat(&changes, &links, toggle, writer));
+
+    if inline_diffs {
+        let mut budget = MAX_INLINE_DIFF_LINES;
+        for (i, change) in changes.iter().enumerate() {
+            try!(generate_inline_diff(tree_name, &git.repo, commit, change, i, &mut budget, writer));
+        }
+    }
+
+    Ok(())
+}
+
+// Links a file changed by `commit` to its diff, or a deleted file to
+// the version in the first parent.
+fn change_link(tree_name: &str, commit: &git2::Commit, change: &FileChange) -> String {
+    if change.status == 'D' {
+        match commit.parent_id(0) {
+            Ok(parent) => format!("/{}/rev/{}/{}", tree_name, parent, change.old_path),
+            Err(_) => "".to_owned(),
+        }
+    } else {
+        format!("/{}/diff/{}/{}", tree_name, commit.id(), change.path)
+    }
+}
+
+// Stop showing diffs inline on a commit page after this many lines.
+const MAX_INLINE_DIFF_LINES: usize = 20000;
+
+// A file changed by a commit, relative to its first parent.
+struct FileChange {
+    status: char,
+    old_path: String,
+    path: String,
+    old_id: Option<git2::Oid>,
+    new_id: Option<git2::Oid>,
+    added: usize,
+    removed: usize,
+    binary: bool,
+    patch: Option<git2::Patch>,
+}
+
+fn is_binary_blob(repo: &git2::Repository, id: Option<git2::Oid>) -> bool {
+    match id.and_then(|id| repo.find_blob(id).ok()) {
+        Some(blob) => blob.is_binary(),
+        None => false,
+    }
+}
+
+// Diffs `commit` against its first parent, detecting renames and
+// copies. For merges, only files that differ from every parent are
+// included, like `git show --cc`.
+fn commit_changes(repo: &git2::Repository, commit: &git2::Commit) -> Result<Vec<FileChange>, &'static str> {
+    let parents = commit.parents().collect::<Vec<_>>();
+    let old_tree = match parents.first() {
+        Some(parent) => Some(try!(parent.tree().map_err(|_| "Bad revision"))),
+        None => None,
+    };
+    let new_tree = try!(commit.tree().map_err(|_| "Bad revision"));
+    let other_parents = if parents.is_empty() { &parents[..] } else { &parents[1..] };
+    tree_changes(repo, old_tree.as_ref(), &new_tree, "", other_parents)
+}
+
+// Diffs two trees, limited to files under `dir` if it isn't empty.
+// Files whose new contents match one of `other_parents` are left out.
+fn tree_changes(repo: &git2::Repository,
+                old_tree: Option<&git2::Tree>,
+                new_tree: &git2::Tree,
+                dir: &str,
+                other_parents: &[git2::Commit]) -> Result<Vec<FileChange>, &'static str> {
+    let mut opts = git2::DiffOptions::new();
+    if !dir.is_empty() {
+        opts.pathspec(dir);
+    }
+
+    counters::count_git_lookup();
+    let mut diff = try!(repo.diff_tree_to_tree(old_tree, Some(new_tree), Some(&mut opts)).map_err(|_| "Diff failed"));
+    let mut find_opts = git2::DiffFindOptions::new();
+    find_opts.renames(true);
+    find_opts.copies(true);
+    try!(diff.find_similar(Some(&mut find_opts)).map_err(|_| "Diff failed"));
+
     let mut changes = Vec::new();
-    for line in lines {
-        if line.len() == 0 {
+    for (i, delta) in diff.deltas().enumerate() {
+        let status = match delta.status() {
+            git2::Delta::Added => 'A',
+            git2::Delta::Deleted => 'D',
+            git2::Delta::Modified => 'M',
+            git2::Delta::Renamed => 'R',
+            git2::Delta::Copied => 'C',
+            git2::Delta::Typechange => 'T',
+            _ => continue,
+        };
+        let old_path = delta.old_file().path().and_then(|p| p.to_str()).unwrap_or("").to_owned();
+        let path = delta.new_file().path().and_then(|p| p.to_str()).unwrap_or("").to_owned();
+        let old_id = if status == 'A' { None } else { Some(delta.old_file().id()) };
+        let new_id = if status == 'D' { None } else { Some(delta.new_file().id()) };
+
+        let path_in_result = if status == 'D' { &old_path } else { &path };
+        if other_parents.iter().any(|parent| path_id(parent, path_in_result) == new_id) {
             continue;
         }
 
-        let suffix = &line[commit.parents().count() ..];
-        let prefix_size = 2 * (commit.parents().count() + 1);
-        let mut data = suffix.splitn(prefix_size + 1, ' ');
-        let data = try!(data.nth(prefix_size).ok_or("Invalid diff output 3"));
-        let file_info = data.split('\t').take(2).collect::<Vec<_>>();
+        let binary = is_binary_blob(repo, old_id) || is_binary_blob(repo, new_id);
+        let patch = if binary {
+            None
+        } else {
+            git2::Patch::from_diff(&diff, i).ok().and_then(|patch| patch)
+        };
+        let (added, removed) = match patch {
+            Some(ref patch) => patch.line_stats().map(|(_, added, removed)| (added, removed)).unwrap_or((0, 0)),
+            None => (0, 0),
+        };
 
-        let f = F::T(format!("<li>{} <a href=\"/{}/diff/{}/{}\">{}</a>",
-                             file_info[0], tree_name, commit.id(), file_info[1], file_info[1]));
-        changes.push(f);
+        changes.push(FileChange {
+            status: status,
+            old_path: old_path,
+            path: path,
+            old_id: old_id,
+            new_id: new_id,
+            added: added,
+            removed: removed,
+            binary: binary,
+            patch: patch,
+        });
     }
+    Ok(changes)
+}
+
+// Writes a table of changed files and how many lines were added and
+// removed in each, with each file linked to `links[i]`.
+fn generate_diffstat(changes: &[FileChange],
+                     links: &[String],
+                     toggle: &str,
+                     writer: &mut Write) -> Result<(), &'static str> {
+    let rows = changes.iter().zip(links).map(|(change, link)| {
+        let name = match change.status {
+            'R' | 'C' => format!("{} &rarr; {}", entity_replace(&change.old_path), entity_replace(&change.path)),
+            _ => entity_replace(&change.path),
+        };
+        let stats = if change.binary {
+            "<td colspan=\"2\">binary</td>".to_owned()
+        } else {
+            format!("<td class=\"diffstat-added\">+{}</td><td class=\"diffstat-removed\">-{}</td>",
+                    change.added, change.removed)
+        };
+        F::T(format!("<tr><td>{}</td><td><a href=\"{}\">{}</a></td>{}</tr>",
+                     change.status, entity_replace(link), name, stats))
+    }).collect::<Vec<_>>();
+
+    let added = changes.iter().map(|c| c.added).sum::<usize>();
+    let removed = changes.iter().map(|c| c.removed).sum::<usize>();
 
     let f = F::Seq(vec![
-        F::S("<ul>"),
-        F::Indent(changes),
-        F::S("</ul>"),
+        F::T(format!("<p>{} files changed, {} insertions(+), {} deletions(-). {}</p>",
+                     changes.len(), added, removed, toggle)),
+        F::S("<table class=\"diffstat\">"),
+        F::Indent(rows),
+        F::S("</table>"),
     ]);
-    try!(output::generate_formatted(writer, &f, 0));
+    output::generate_formatted(writer, &f, 0)
+}
+
+// Highlights a blob for an inline diff. Returns no lines for files that
+// can't be highlighted, in which case the diff is shown unhighlighted.
+fn highlight_blob(repo: &git2::Repository, id: Option<git2::Oid>, path: &str) -> Vec<String> {
+    let blob = match id.and_then(|id| repo.find_blob(id).ok()) {
+        Some(blob) => blob,
+        None => return vec![],
+    };
+    let format = match languages::select_formatting(path) {
+        FormatAs::Binary => return vec![],
+        FormatAs::FormatDoc(_) => FormatAs::Plain,
+        format => format,
+    };
+    let jumps = HashMap::new();
+    let (lines, _) = format_code(&jumps, format, path, &decode_bytes(blob.content().to_vec()), &[]);
+    lines
+}
+
+fn generate_inline_diff(tree_name: &str,
+                        repo: &git2::Repository,
+                        commit: &git2::Commit,
+                        change: &FileChange,
+                        index: usize,
+                        budget: &mut usize,
+                        writer: &mut Write) -> Result<(), &'static str> {
+    let heading = format!("<h4 id=\"diff-{}\"><a href=\"{}\">{}</a></h4>",
+                          index, entity_replace(&change_link(tree_name, commit, change)),
+                          entity_replace(&change.path));
+    try!(output::generate_formatted(writer, &F::T(heading), 0));
+
+    let patch = match change.patch {
+        Some(ref patch) if !change.binary => patch,
+        _ => {
+            return output::generate_formatted(writer, &F::S("<p>Binary file not shown.</p>"), 0);
+        },
+    };
+    let num_lines = (0 .. patch.num_hunks()).map(|h| patch.num_lines_in_hunk(h).unwrap_or(0)).sum::<usize>();
+    if num_lines > *budget {
+        *budget = 0;
+        return output::generate_formatted(writer, &F::S("<p>Diff too large to show here.</p>"), 0);
+    }
+    *budget -= num_lines;
+
+    let old_lines = highlight_blob(repo, change.old_id, &change.old_path);
+    let new_lines = highlight_blob(repo, change.new_id, &change.path);
+
+    write!(writer, "<pre class=\"commit-diff\">").unwrap();
+    for h in 0 .. patch.num_hunks() {
+        let (hunk, _) = try!(patch.hunk(h).map_err(|_| "Diff failed"));
+        let header = String::from_utf8_lossy(hunk.header());
+        write!(writer, "<code class=\"hunk-header\">{}\n</code>", entity_replace(header.trim_right())).unwrap();
+
+        for l in 0 .. patch.num_lines_in_hunk(h).unwrap_or(0) {
+            let line = try!(patch.line_in_hunk(h, l).map_err(|_| "Diff failed"));
+            let (class, highlighted) = match line.origin() {
+                '+' => (" class=\"plus-line\"", line.new_lineno().and_then(|n| new_lines.get(n as usize - 1))),
+                '-' => (" class=\"minus-line\"", line.old_lineno().and_then(|n| old_lines.get(n as usize - 1))),
+                ' ' => ("", line.new_lineno().and_then(|n| new_lines.get(n as usize - 1))),
+                _ => continue,
+            };
+            let content = match highlighted {
+                Some(content) => content.clone(),
+                None => {
+                    let content = String::from_utf8_lossy(line.content()).into_owned();
+                    entity_replace(content.trim_right_matches('\n'))
+                },
+            };
+            write!(writer, "<code{}>{} {}\n</code>", class, line.origin(), content).unwrap();
+        }
+    }
+    write!(writer, "</pre>").unwrap();
 
     Ok(())
 }
@@ -816,10 +2328,12 @@ fn generate_commit_info(tree_name: &str,
 pub fn format_commit(cfg: &config::Config,
                      tree_name: &str,
                      rev: &str,
+                     inline_diffs: bool,
                      writer: &mut Write) -> Result<(), &'static str> {
     let tree_config = try!(cfg.trees.get(tree_name).ok_or("Invalid tree"));
 
     let git = try!(config::get_git(tree_config));
+    counters::count_git_lookup();
     let commit_obj = try!(git.repo.revparse_single(rev).map_err(|_| "Bad revision"));
     let commit = try!(commit_obj.as_commit().ok_or("Bad revision"));
 
@@ -833,9 +2347,115 @@ pub fn format_commit(cfg: &config::Config,
 
     try!(output::generate_header(&opt, writer));
 
-    try!(generate_commit_info(tree_name, &tree_config, writer, commit));
+    try!(generate_commit_info(tree_name, &tree_config, writer, commit, inline_diffs));
 
     output::generate_footer(&opt, tree_name, "", writer).unwrap();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Repository;
+    use std::fs;
+    use testing::{commit, TempDir};
+
+    // A revision added to the ignore list after the blame repository
+    // was built is skipped when a file is rendered with the config
+    // loaded the way output-file loads it.
+    #[test]
+    fn test_render_ignored_rev() {
+        let dir = TempDir::new("format");
+        for sub in &["index", "files"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        let repo = Repository::init_bare(dir.join("repo")).unwrap();
+        let blame_repo = Repository::init_bare(dir.join("blame")).unwrap();
+
+        let first = commit(&repo, "A", &[("a.txt", "int x;\nint y;\n")], "Add a.txt", &[]);
+        let reformat = commit(&repo, "A", &[("a.txt", "int  x;\nint y;\n")], "Reformat", &[first]);
+        let blame_first = commit(&blame_repo, "A", &[("a.txt", &format!("{}:%:1:A\n{}:%:2:A\n", first, first))],
+                                 &format!("blame-format 1\ngit {}\n", first), &[]);
+        let blame_reformat = commit(&blame_repo, "A", &[("a.txt", &format!("{}:%:1:A\n{}:%:2:A\n", reformat, first))],
+                                    &format!("blame-format 1\ngit {}\n", reformat), &[blame_first]);
+        blame_repo.reference("refs/heads/master", blame_reformat, true, "test").unwrap();
+
+        fs::File::create(dir.join("ignore-revs")).unwrap().write_all(format!("{}\n", reformat).as_bytes()).unwrap();
+        let config = format!("{{\"mozsearch_path\": \"{0}\", \"trees\": {{\"t\": {{\
+                              \"index_path\": \"{0}/index\", \"files_path\": \"{0}/files\", \
+                              \"objdir_path\": \"{0}/objdir\", \"git_path\": \"{0}/repo\", \
+                              \"git_blame_path\": \"{0}/blame\", \"ignore_revs_path\": \"{0}/ignore-revs\"}}}}}}",
+                             dir.display());
+        let config_path = dir.join("config.json");
+        fs::File::create(&config_path).unwrap().write_all(config.as_bytes()).unwrap();
+
+        let cfg = config::load(config_path.to_str().unwrap(), true).unwrap();
+        let git = cfg.trees["t"].git.as_ref().unwrap();
+        let commit = git.repo.find_commit(reformat).unwrap();
+        let blame_commit = git.blame_repo.find_commit(blame_reformat).unwrap();
+        let mut output = Vec::new();
+        format_file_data(&cfg, "t", &[], Some(&commit), Some(&blame_commit), "a.txt",
+                         "int  x;\nint y;\n".to_owned(), &HashMap::new(), &[], false, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains(&format!("blame-ignored\" data-blame=\"{}#%#1#{}\"", first, reformat)), "{}", output);
+        assert!(output.contains(&format!("data-blame=\"{}#%#2\"", first)), "{}", output);
+    }
+
+    fn lost(lines: &[(&str, &[bool])]) -> Vec<(String, Vec<bool>)> {
+        lines.iter().map(|&(line, flags)| (line.to_owned(), flags.to_vec())).collect()
+    }
+
+    #[test]
+    fn test_coalesce_lost_lines() {
+        // Removed from both parents.
+        let mut lines = Vec::new();
+        coalesce_lost_lines(&mut lines, vec!["a".to_owned()], 0, 2);
+        coalesce_lost_lines(&mut lines, vec!["a".to_owned()], 1, 2);
+        assert_eq!(lines, lost(&[("a", &[true, true])]));
+
+        // Removed from one parent only.
+        let mut lines = Vec::new();
+        coalesce_lost_lines(&mut lines, vec![], 0, 2);
+        coalesce_lost_lines(&mut lines, vec!["b".to_owned()], 1, 2);
+        assert_eq!(lines, lost(&[("b", &[false, true])]));
+
+        // Lines removed from either parent are kept in order around the
+        // ones removed from both.
+        let mut lines = Vec::new();
+        coalesce_lost_lines(&mut lines, vec!["a".to_owned(), "b".to_owned(), "c".to_owned()], 0, 2);
+        coalesce_lost_lines(&mut lines, vec!["x".to_owned(), "b".to_owned(), "y".to_owned()], 1, 2);
+        assert_eq!(lines, lost(&[("a", &[true, false]), ("x", &[false, true]), ("b", &[true, true]),
+                                 ("c", &[true, false]), ("y", &[false, true])]));
+    }
+
+    #[test]
+    fn test_combined_diff() {
+        let dir = TempDir::new("combined-diff");
+        let repo = Repository::init_bare(&*dir).unwrap();
+
+        let base = commit(&repo, "A", &[("m.txt", "a\nb\nc\nd\ne\nf\n")], "base", &[]);
+        let left = commit(&repo, "A", &[("m.txt", "a\nb2\nc\nD\ne\nf\n")], "left", &[base]);
+        let right = commit(&repo, "A", &[("m.txt", "a\nB\nc\nd\nE\nf\n")], "right", &[base]);
+        let merge = commit(&repo, "A", &[("m.txt", "a\nmerged\nc\nD\nE\nf\nnew\n")], "merge", &[left, right]);
+
+        // As shown by `git diff-tree --cc --patience -U100000`.
+        let expected = [
+            "  a", "- b2", " -B", "++merged", "  c", " -d", " +D", "- e", "+ E", "  f", "++new",
+        ];
+        let diff = combined_diff(&repo, &repo.find_commit(merge).unwrap(), "m.txt").unwrap().unwrap();
+        let diff = diff.iter().map(|&(ref origin, ref line)| {
+            format!("{}{}", origin.iter().cloned().collect::<String>(), line)
+        }).collect::<Vec<_>>();
+        assert_eq!(diff, expected);
+
+        // A clean merge only takes changes from the parents, so it isn't
+        // shown, as `--cc` with its default context leaves it out.
+        let base = commit(&repo, "A", &[("m.txt", "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n")], "base", &[]);
+        let left = commit(&repo, "A", &[("m.txt", "1\nL2\n3\n4\n5\n6\n7\n8\n9\n10\n")], "left", &[base]);
+        let right = commit(&repo, "A", &[("m.txt", "1\n2\n3\n4\n5\n6\n7\n8\nR9\n10\n")], "right", &[base]);
+        let clean = commit(&repo, "A", &[("m.txt", "1\nL2\n3\n4\n5\n6\n7\n8\nR9\n10\n")], "clean", &[left, right]);
+        assert!(combined_diff(&repo, &repo.find_commit(clean).unwrap(), "m.txt").unwrap().is_none());
+    }
+}
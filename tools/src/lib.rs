@@ -9,10 +9,17 @@ pub mod file_format;
 
 pub mod config;
 pub mod blame;
+pub mod blame_map;
 pub mod output;
 pub mod languages;
 pub mod format;
 pub mod tokenize;
+pub mod word_diff;
+pub mod counters;
+
+// Test fixtures, public so that the tests of the tools can use them too.
+#[doc(hidden)]
+pub mod testing;
 
 pub fn find_source_file(path: &str, files_root: &str, objdir: &str) -> String {
     if path.starts_with("__GENERATED__") {
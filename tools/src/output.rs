@@ -11,6 +11,12 @@ pub struct Options<'a> {
     pub include_date: bool,
 }
 
+// Escapes text for HTML. Quotes are escaped too, so that the result
+// can go in attribute values.
+pub fn entity_replace(s: &str) -> String {
+    s.replace("&", "&amp;").replace("<", "&lt;").replace("\"", "&quot;")
+}
+
 pub fn choose_icon(path: &str) -> String {
     let ext : &str = match Path::new(path).extension() {
         Some(ext) => ext.to_str().unwrap(),
@@ -26,7 +32,10 @@ pub fn choose_icon(path: &str) -> String {
 }
 
 pub fn file_url(opt: &Options, path: &str) -> String {
-    format!("/{}/source/{}", opt.tree_name, path)
+    match opt.revision {
+        Some((rev_id, _)) => format!("/{}/rev/{}/{}", opt.tree_name, rev_id, path),
+        None => format!("/{}/source/{}", opt.tree_name, path),
+    }
 }
 
 pub fn generate_breadcrumbs(opt: &Options, writer: &mut Write, path: &str) -> Result<(), &'static str>
@@ -34,7 +43,7 @@ pub fn generate_breadcrumbs(opt: &Options, writer: &mut Write, path: &str) -> Re
     let mut breadcrumbs = format!("<a href=\"{}\">{}</a>", file_url(opt, ""), opt.tree_name);
 
     let mut path_so_far = "".to_string();
-    for name in path.split('/') {
+    for name in path.split('/').filter(|name| !name.is_empty()) {
         breadcrumbs.push_str("<span class=\"path-separator\">/</span>");
         path_so_far.push_str(name);
         breadcrumbs.push_str(&format!("<a href=\"{}\">{}</a>", file_url(opt, &path_so_far), name));
@@ -250,12 +259,14 @@ pub fn generate_footer(opt: &Options, tree_name: &str, path: &str, writer: &mut
     Ok(())
 }
 
+#[derive(Clone)]
 pub struct PanelItem {
     pub title: String,
     pub link: String,
     pub update_link_lineno: bool,
 }
 
+#[derive(Clone)]
 pub struct PanelSection {
     pub name: String,
     pub items: Vec<PanelItem>,
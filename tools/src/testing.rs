@@ -0,0 +1,57 @@
+// Fixtures shared by the unit tests of the library and the tools.
+
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use git2::{Oid, Repository, Signature, Time};
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+// A fresh directory under the system temp dir. It's removed when the
+// guard is dropped, also when the test panics.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+        let n = NEXT_DIR.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("mozsearch-{}-{}-{}", name, nanos, n));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path: path }
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// Commits a tree holding just `files` to `repo`, without moving any
+// reference.
+pub fn commit(repo: &Repository, author: &str, files: &[(&str, &str)], message: &str, parents: &[Oid]) -> Oid {
+    let mut builder = repo.treebuilder(None).unwrap();
+    for &(name, data) in files {
+        builder.insert(name, repo.blob(data.as_bytes()).unwrap(), 0o100644).unwrap();
+    }
+    let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+    let sig = Signature::new(author, "author@example.com", &Time::new(0, 0)).unwrap();
+    let parents = parents.iter().map(|p| repo.find_commit(*p).unwrap()).collect::<Vec<_>>();
+    let parent_refs = parents.iter().collect::<Vec<_>>();
+    repo.commit(None, &sig, &sig, message, &tree, &parent_refs).unwrap()
+}
@@ -0,0 +1,220 @@
+// Finds the words that changed between two versions of a line, so
+// that diffs can show them within lines that were otherwise kept.
+
+// Lines with more words than this aren't compared, since finding the
+// common words takes time proportional to the product of the lengths.
+const MAX_WORDS: usize = 500;
+
+const CHANGE_START: &'static str = "<span class=\"diff-change\">";
+const CHANGE_END: &'static str = "</span>";
+
+#[derive(PartialEq)]
+enum CharClass {
+    Word,
+    Space,
+    Other,
+}
+
+fn char_class(c: char) -> CharClass {
+    if c.is_alphanumeric() || c == '_' {
+        CharClass::Word
+    } else if c.is_whitespace() {
+        CharClass::Space
+    } else {
+        CharClass::Other
+    }
+}
+
+// A word, run of whitespace or punctuation character in a line, with
+// its start and end as char offsets.
+struct Word<'a> {
+    start: usize,
+    end: usize,
+    text: &'a str,
+}
+
+fn split_words<'a>(line: &'a str) -> Vec<Word<'a>> {
+    let mut words = Vec::new();
+    let mut start = 0;
+    let mut start_byte = 0;
+    let mut last_class = None;
+    let mut len = 0;
+    for (byte_index, c) in line.char_indices() {
+        let class = char_class(c);
+        let continues = match last_class {
+            Some(ref last) => *last == class && class != CharClass::Other,
+            None => true,
+        };
+        if !continues {
+            words.push(Word { start: start, end: len, text: &line[start_byte .. byte_index] });
+            start = len;
+            start_byte = byte_index;
+        }
+        last_class = Some(class);
+        len += 1;
+    }
+    if len > 0 {
+        words.push(Word { start: start, end: len, text: &line[start_byte ..] });
+    }
+    words
+}
+
+// Marks which words of `old` and `new` are part of their longest
+// common subsequence.
+fn common_words(old: &[Word], new: &[Word]) -> (Vec<bool>, Vec<bool>) {
+    let width = new.len() + 1;
+    let mut lengths = vec![0; (old.len() + 1) * width];
+    for i in (0 .. old.len()).rev() {
+        for j in (0 .. new.len()).rev() {
+            lengths[i * width + j] = if old[i].text == new[j].text {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                ::std::cmp::max(lengths[(i + 1) * width + j], lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut old_kept = vec![false; old.len()];
+    let mut new_kept = vec![false; new.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i].text == new[j].text {
+            old_kept[i] = true;
+            new_kept[j] = true;
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    (old_kept, new_kept)
+}
+
+// Turns the words that weren't kept into ranges of chars. Whitespace
+// between two changed words is included so that a changed phrase is
+// one range.
+fn changed_ranges(words: &[Word], kept: &[bool]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for (i, word) in words.iter().enumerate() {
+        if kept[i] {
+            continue;
+        }
+        if let Some(last) = ranges.last_mut() {
+            let gap = &words[i - 1];
+            if last.1 == word.start ||
+                (last.1 == gap.start && char_class(gap.text.chars().next().unwrap()) == CharClass::Space) {
+                last.1 = word.end;
+                continue;
+            }
+        }
+        ranges.push((word.start, word.end));
+    }
+    ranges
+}
+
+fn non_space_len(words: &[Word], kept: Option<&[bool]>) -> usize {
+    words.iter().enumerate().filter(|&(i, word)| {
+        char_class(word.text.chars().next().unwrap()) != CharClass::Space &&
+            kept.map(|kept| kept[i]).unwrap_or(true)
+    }).map(|(_, word)| word.end - word.start).sum()
+}
+
+// Returns the ranges of chars that changed in `old` and in `new`.
+// Returns None if the lines are too long to compare, or if less than
+// half of the longer one was kept, since showing most of a line as
+// changed isn't any more useful than coloring the whole line.
+pub fn line_changes(old: &str, new: &str) -> Option<(Vec<(usize, usize)>, Vec<(usize, usize)>)> {
+    let old_words = split_words(old);
+    let new_words = split_words(new);
+    if old_words.len() > MAX_WORDS || new_words.len() > MAX_WORDS {
+        return None;
+    }
+
+    let (old_kept, new_kept) = common_words(&old_words, &new_words);
+    let kept_len = non_space_len(&old_words, Some(&old_kept));
+    let longest = ::std::cmp::max(non_space_len(&old_words, None), non_space_len(&new_words, None));
+    if kept_len * 2 < longest {
+        return None;
+    }
+
+    Some((changed_ranges(&old_words, &old_kept), changed_ranges(&new_words, &new_kept)))
+}
+
+// Wraps the text of a highlighted line that falls in `ranges` (as
+// char offsets into the line's text, not its HTML) in spans. A span
+// is closed before each tag and reopened after it, so that it nests
+// within the spans from syntax highlighting.
+pub fn mark_changes(html: &str, ranges: &[(usize, usize)]) -> String {
+    let mut result = String::with_capacity(html.len() + ranges.len() * CHANGE_START.len());
+    let mut ranges = ranges.iter().peekable();
+    let mut pos = 0;
+    let mut open = false;
+    let mut rest = html;
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            let len = rest.find('>').map(|end| end + 1).unwrap_or(rest.len());
+            if open {
+                result.push_str(CHANGE_END);
+                open = false;
+            }
+            result.push_str(&rest[.. len]);
+            rest = &rest[len ..];
+            continue;
+        }
+
+        // Entities stand for a single char of the line's text.
+        let len = if c == '&' {
+            rest.find(';').map(|end| end + 1).unwrap_or(1)
+        } else {
+            c.len_utf8()
+        };
+
+        while ranges.peek().map(|&&(_, end)| end <= pos).unwrap_or(false) {
+            ranges.next();
+        }
+        let in_range = ranges.peek().map(|&&(start, _)| start <= pos).unwrap_or(false);
+        if in_range && !open {
+            result.push_str(CHANGE_START);
+            open = true;
+        } else if !in_range && open {
+            result.push_str(CHANGE_END);
+            open = false;
+        }
+
+        result.push_str(&rest[.. len]);
+        rest = &rest[len ..];
+        pos += 1;
+    }
+    if open {
+        result.push_str(CHANGE_END);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_changes() {
+        let (old, new) = line_changes("  return 0;", "  return y;").unwrap();
+        assert_eq!(old, vec![(9, 10)]);
+        assert_eq!(new, vec![(9, 10)]);
+
+        let (old, new) = line_changes("foo(a, b, c)", "foo(a, x y, c)").unwrap();
+        assert_eq!(old, vec![(7, 8)]);
+        assert_eq!(new, vec![(7, 10)]);
+
+        assert!(line_changes("int x = 1;", "while (true) {").is_none());
+    }
+
+    #[test]
+    fn test_mark_changes() {
+        assert_eq!(mark_changes("<b>return</b> a &lt; b;", &[(7, 10)]),
+                   "<b>return</b> <span class=\"diff-change\">a &lt;</span> b;");
+        assert_eq!(mark_changes("a<b>cd</b>e", &[(0, 4)]),
+                   "<span class=\"diff-change\">a</span><b><span class=\"diff-change\">cd</span></b><span class=\"diff-change\">e</span>");
+    }
+}